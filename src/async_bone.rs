@@ -0,0 +1,341 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+
+use crate::codec::{self, Frame, FrameLayout};
+use crate::models::{self, Request};
+use crate::{
+	auth_command, auth_result, check_session, decode_msgpack_response, decode_response,
+	encode_command, msgpack, raw, tls, transport, BoneError, PeerTrust, RawData, Result, TlsConfig,
+};
+
+trait IsAsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> IsAsyncStream for T {}
+
+/// Async client for tokio, with the same commands as [`crate::Bone`].
+///
+/// Dropping a request future before it completed drops the connection, call
+/// `connect` again afterwards.
+///
+/// ```no_run
+/// # use serde_json::json;
+/// # async fn example() -> bone_api::Result<()> {
+/// let mut bone = bone_api::AsyncBone::new("192.168.1.10", "6450", false, false);
+/// bone.connect().await?;
+///
+/// let response = bone.send_command(&json!({"command": "date"})).await?;
+/// println!("{}", response["payload"]["date"]);
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncBone {
+	ip: String,
+	port: String,
+	stream: Option<Box<dyn IsAsyncStream>>,
+	enable_msgpack: bool,
+	use_ssl: bool,
+	tls_config: TlsConfig,
+	peer_trust: Option<PeerTrust>,
+	peer_fingerprint: Option<String>,
+	max_frame_size: usize,
+	connect_timeout: Option<Duration>,
+	read_timeout: Option<Duration>,
+	write_timeout: Option<Duration>,
+	keepalive: Option<Duration>,
+}
+
+/// Runs `future`, failing with [`BoneError::Timeout`] if it takes longer than `timeout`.
+async fn with_timeout<T>(
+	timeout: Option<Duration>,
+	future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+	match timeout {
+		Some(timeout) => tokio::time::timeout(timeout, future)
+			.await
+			.map_err(|_| BoneError::Timeout)?,
+		None => future.await,
+	}
+}
+
+/// Runs blocking work, like reading certificates, on tokio's blocking thread pool.
+async fn spawn_blocking<T: Send + 'static>(
+	f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+	tokio::task::spawn_blocking(f)
+		.await
+		.map_err(|e| BoneError::Io(std::io::Error::other(e)))?
+}
+
+impl AsyncBone {
+	async fn open_socket(&self) -> Result<TcpStream> {
+		let port = transport::parse_port(&self.port)?;
+
+		let addrs: Vec<SocketAddr> = match transport::parse_ip_literal(&self.ip, port)? {
+			Some(addr) => vec![addr],
+			None => tokio::net::lookup_host((self.ip.as_str(), port))
+				.await?
+				.collect(),
+		};
+
+		let mut last_err = None;
+
+		for addr in addrs {
+			match with_timeout(self.connect_timeout, async {
+				Ok(TcpStream::connect(addr).await?)
+			})
+			.await
+			{
+				Ok(stream) => return Ok(stream),
+				Err(e) => last_err = Some(e),
+			}
+		}
+
+		Err(last_err.unwrap_or_else(|| {
+			BoneError::Io(std::io::Error::new(
+				std::io::ErrorKind::NotFound,
+				"could not resolve address",
+			))
+		}))
+	}
+
+	async fn send_frame(&mut self, command: &Value, layout: FrameLayout) -> Result<Frame> {
+		let send_data = encode_command(command, self.enable_msgpack)?;
+		let (read_timeout, write_timeout) = (self.read_timeout, self.write_timeout);
+		let max_frame_size = self.max_frame_size;
+
+		// taken for the whole exchange, so a dropped future leaves the client
+		// disconnected instead of with a response still pending on the stream
+		let mut stream = self.stream.take().ok_or(BoneError::NotConnected)?;
+
+		let result = async {
+			with_timeout(
+				write_timeout,
+				codec::write_request_async(&mut stream, &send_data),
+			)
+			.await?;
+			with_timeout(
+				read_timeout,
+				codec::read_frame_async(&mut stream, layout, max_frame_size),
+			)
+			.await
+		}
+		.await;
+
+		// after a transport or framing error the stream position is unknown
+		if !matches!(
+			result,
+			Err(BoneError::Io(_) | BoneError::Timeout | BoneError::Framing(_) | BoneError::Tls(_))
+		) {
+			self.stream = Some(stream);
+		}
+
+		result
+	}
+
+	pub fn new(ip: &str, port: &str, enable_msgpack: bool, use_ssl: bool) -> AsyncBone {
+		AsyncBone {
+			ip: ip.to_string(),
+			port: port.to_string(),
+			stream: None,
+			enable_msgpack,
+			use_ssl,
+			tls_config: TlsConfig::default(),
+			peer_trust: None,
+			peer_fingerprint: None,
+			max_frame_size: codec::DEFAULT_MAX_FRAME_SIZE,
+			connect_timeout: None,
+			read_timeout: None,
+			write_timeout: None,
+			keepalive: None,
+		}
+	}
+
+	/// See [`Bone::set_msgpack`](crate::Bone::set_msgpack).
+	pub fn set_msgpack(&mut self, enable_msgpack: bool) {
+		self.enable_msgpack = enable_msgpack;
+	}
+
+	pub fn msgpack(&self) -> bool {
+		self.enable_msgpack
+	}
+
+	/// Sets how the device certificate is verified on encrypted connections.
+	pub fn set_tls_config(&mut self, tls_config: TlsConfig) {
+		self.tls_config = tls_config;
+	}
+
+	/// Name the device is stored under in the known hosts file.
+	pub fn known_hosts_name(&self) -> String {
+		self.tls_config.known_hosts_name(&self.ip, &self.port)
+	}
+
+	/// How the certificate of the current encrypted connection was trusted.
+	pub fn peer_trust(&self) -> Option<PeerTrust> {
+		self.peer_trust
+	}
+
+	/// SHA-256 fingerprint of the certificate of the current encrypted connection.
+	pub fn peer_fingerprint(&self) -> Option<&str> {
+		self.peer_fingerprint.as_deref()
+	}
+
+	/// Largest response accepted before the connection is considered broken.
+	/// Defaults to [`codec::DEFAULT_MAX_FRAME_SIZE`].
+	pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+		self.max_frame_size = max_frame_size;
+	}
+
+	/// Limits how long `connect` waits for the TCP connection to be established.
+	pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+		self.connect_timeout = timeout;
+	}
+
+	/// Limits how long receiving a response and the TLS handshake may take.
+	pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+		self.read_timeout = timeout;
+	}
+
+	/// Limits how long sending a request may take.
+	pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+		self.write_timeout = timeout;
+	}
+
+	/// Sends TCP keepalive probes after `time` without traffic, see
+	/// [`Bone::set_keepalive`](crate::Bone::set_keepalive). Applies from the next `connect`.
+	pub fn set_keepalive(&mut self, time: Option<Duration>) {
+		self.keepalive = time;
+	}
+
+	/// Connects to the device. Reading certificates and the known hosts file
+	/// runs on tokio's blocking thread pool.
+	pub async fn connect(&mut self) -> Result<()> {
+		let stream = self.open_socket().await?;
+
+		if self.keepalive.is_some() {
+			transport::set_tcp_keepalive(SockRef::from(&stream), self.keepalive)?;
+		}
+
+		if self.use_ssl {
+			let tls_config = self.tls_config.clone();
+			let ssl = spawn_blocking(move || tls_config.configure())
+				.await?
+				.into_ssl(self.tls_config.server_name(&self.ip))?;
+
+			let mut stream = SslStream::new(ssl, stream)?;
+			with_timeout(self.read_timeout, async {
+				Ok(Pin::new(&mut stream).connect().await?)
+			})
+			.await?;
+
+			self.peer_fingerprint = tls::peer_fingerprint(stream.ssl())?;
+
+			let tls_config = self.tls_config.clone();
+			let cert = stream.ssl().peer_certificate();
+			let verify_result = stream.ssl().verify_result();
+			let name = self.known_hosts_name();
+			self.peer_trust = Some(
+				spawn_blocking(move || tls_config.verify_certificate(cert, verify_result, &name))
+					.await?,
+			);
+
+			self.stream = Some(Box::new(stream));
+		} else {
+			self.peer_fingerprint = None;
+			self.peer_trust = None;
+			self.stream = Some(Box::new(stream));
+		}
+
+		Ok(())
+	}
+
+	/// Whether a connection is established. Turns false after the connection
+	/// broke, until `connect` is called again.
+	pub fn is_connected(&self) -> bool {
+		self.stream.is_some()
+	}
+
+	pub fn disconnect(&mut self) {
+		self.stream = None;
+	}
+
+	pub async fn send_raw_command(&mut self, command: &Value) -> Result<(i32, Vec<u8>)> {
+		let frame = self.send_frame(command, FrameLayout::Positioned).await?;
+
+		Ok((frame.position.unwrap_or_default(), frame.payload))
+	}
+
+	pub async fn send_sync_command(&mut self, command: &Value) -> Result<(i32, RawData)> {
+		let filter = raw::sync_filter(command)?;
+
+		let (last_position, buffer) = self.send_raw_command(command).await?;
+
+		Ok((last_position, raw::decode_sync(&filter, &buffer)))
+	}
+
+	pub async fn send_ks_command(&mut self, command: &Value) -> Result<(i32, RawData)> {
+		let (command, channel) = raw::ks_command(command);
+
+		let (last_position, buffer) = self.send_raw_command(&command).await?;
+
+		Ok((last_position, raw::decode_ks(channel, &buffer)))
+	}
+
+	pub async fn send_ks_sync_command(&mut self, command: &Value) -> Result<(i32, RawData)> {
+		let (last_position, buffer) = self.send_raw_command(command).await?;
+
+		Ok((last_position, raw::decode_ks_sync(&buffer)?))
+	}
+
+	pub async fn send_dv_command(&mut self, command: &Value) -> Result<Vec<f32>> {
+		let frame = self.send_frame(command, FrameLayout::Plain).await?;
+
+		raw::decode_dv(&frame.payload)
+	}
+
+	pub async fn send_command(&mut self, command: &Value) -> Result<Value> {
+		let buffer = self.send_frame(command, FrameLayout::Plain).await?.payload;
+
+		let response = decode_response(buffer)?;
+
+		check_session(response["payload"]["error"].as_str())?;
+		Ok(response)
+	}
+
+	/// See [`Bone::send_command_msgpack`](crate::Bone::send_command_msgpack).
+	pub async fn send_command_msgpack(&mut self, command: &Value) -> Result<msgpack::Value> {
+		let buffer = self.send_frame(command, FrameLayout::Plain).await?.payload;
+		let response = decode_msgpack_response(buffer)?;
+
+		check_session(response["payload"]["error"].as_str())?;
+		Ok(response)
+	}
+
+	/// Sends `request` and decodes the payload of the response, see [`Bone::call`](crate::Bone::call).
+	pub async fn call<Req: Request, Resp: DeserializeOwned>(
+		&mut self,
+		request: &Req,
+	) -> Result<Resp> {
+		let response = self.send_command(&models::command(request)?).await?;
+
+		models::payload(&response)
+	}
+
+	pub async fn login(&mut self, username: &str, password: &str) -> Result<String> {
+		let response = self
+			.send_command(&json!({"command": "request_token"}))
+			.await?;
+
+		let command = auth_command(&response, username, password)?;
+		let response = self.send_command(&command).await?;
+
+		auth_result(&response)
+	}
+}
@@ -0,0 +1,440 @@
+//! Wire format of the BeMoS API.
+//!
+//! A request is the serialized command followed by `\r\n`. A response starts with
+//! the length of the rest of the frame as 8 hex digits, followed by the payload.
+//! Responses to raw data commands carry the 4 byte big-endian buffer position in
+//! front of the payload, which is included in the length.
+
+use std::io::{Read, Write};
+
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{BoneError, Result};
+
+/// Size of the hex encoded length header in front of every response.
+pub const LENGTH_HEADER_SIZE: usize = 8;
+/// Size of the position in front of the payload of positioned responses.
+pub const POSITION_SIZE: usize = 4;
+/// Terminator appended to every request.
+pub const REQUEST_TERMINATOR: &[u8] = b"\r\n";
+/// Largest response accepted unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Ways a response can violate the wire format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FramingError {
+	/// The length header is not made up of 8 hex digits.
+	InvalidHeader([u8; LENGTH_HEADER_SIZE]),
+	/// The announced length exceeds the configured maximum.
+	TooLarge { len: usize, max: usize },
+	/// The announced length is too short for the position of a positioned frame.
+	TooShort { len: usize },
+}
+
+impl std::fmt::Display for FramingError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			FramingError::InvalidHeader(header) => {
+				write!(
+					f,
+					"invalid length header {:?}",
+					String::from_utf8_lossy(header)
+				)
+			}
+			FramingError::TooLarge { len, max } => {
+				write!(f, "frame length {} exceeds maximum of {}", len, max)
+			}
+			FramingError::TooShort { len } => {
+				write!(f, "frame length {} too short for position header", len)
+			}
+		}
+	}
+}
+
+/// Layout of a response frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameLayout {
+	/// Length header followed by the payload.
+	Plain,
+	/// Length header followed by the buffer position and the payload.
+	Positioned,
+}
+
+/// A decoded response frame.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Frame {
+	/// Buffer position, only present in positioned frames.
+	pub position: Option<i32>,
+	pub payload: Vec<u8>,
+}
+
+impl Frame {
+	pub fn plain(payload: Vec<u8>) -> Frame {
+		Frame {
+			position: None,
+			payload,
+		}
+	}
+
+	pub fn positioned(position: i32, payload: Vec<u8>) -> Frame {
+		Frame {
+			position: Some(position),
+			payload,
+		}
+	}
+
+	pub fn layout(&self) -> FrameLayout {
+		match self.position {
+			Some(_) => FrameLayout::Positioned,
+			None => FrameLayout::Plain,
+		}
+	}
+
+	/// Encodes the frame the way a device sends it.
+	pub fn encode(&self) -> Vec<u8> {
+		let position = self.position.map(i32::to_be_bytes);
+		let position: &[u8] = match position {
+			Some(ref position) => position,
+			None => &[],
+		};
+
+		let header = format!("{:08x}", position.len() + self.payload.len());
+
+		[header.as_bytes(), position, &self.payload].concat()
+	}
+
+	/// Decodes a frame from the start of `buffer`. Returns `None` if the buffer
+	/// does not contain a complete frame yet, otherwise the frame and the number
+	/// of bytes it occupied.
+	pub fn decode(
+		buffer: &[u8],
+		layout: FrameLayout,
+		max_frame_size: usize,
+	) -> Result<Option<(Frame, usize)>> {
+		if buffer.len() < LENGTH_HEADER_SIZE {
+			return Ok(None);
+		}
+
+		let mut header = [0; LENGTH_HEADER_SIZE];
+		header.copy_from_slice(&buffer[..LENGTH_HEADER_SIZE]);
+		let len = check_length(parse_length_header(&header)?, layout, max_frame_size)?;
+
+		let end = LENGTH_HEADER_SIZE + len;
+		if buffer.len() < end {
+			return Ok(None);
+		}
+
+		let frame = split_body(&buffer[LENGTH_HEADER_SIZE..end], layout)?;
+
+		Ok(Some((frame, end)))
+	}
+}
+
+/// Parses the hex encoded length header of a response.
+pub fn parse_length_header(header: &[u8; LENGTH_HEADER_SIZE]) -> Result<usize> {
+	if !header.iter().all(u8::is_ascii_hexdigit) {
+		return Err(BoneError::Framing(FramingError::InvalidHeader(*header)));
+	}
+
+	// eight hex digits always fit into an u32
+	let s = std::str::from_utf8(header).unwrap_or_default();
+	let len = u32::from_str_radix(s, 16).unwrap_or_default();
+
+	Ok(len as usize)
+}
+
+/// Validates a parsed length against the layout and the maximum frame size.
+pub fn check_length(len: usize, layout: FrameLayout, max_frame_size: usize) -> Result<usize> {
+	if len > max_frame_size {
+		return Err(BoneError::Framing(FramingError::TooLarge {
+			len,
+			max: max_frame_size,
+		}));
+	}
+
+	if layout == FrameLayout::Positioned && len < POSITION_SIZE {
+		return Err(BoneError::Framing(FramingError::TooShort { len }));
+	}
+
+	Ok(len)
+}
+
+fn split_body(body: &[u8], layout: FrameLayout) -> Result<Frame> {
+	match layout {
+		FrameLayout::Plain => Ok(Frame::plain(body.to_vec())),
+		FrameLayout::Positioned => {
+			let mut position = [0; POSITION_SIZE];
+			position.copy_from_slice(&body[..POSITION_SIZE]);
+
+			Ok(Frame::positioned(
+				i32::from_be_bytes(position),
+				body[POSITION_SIZE..].to_vec(),
+			))
+		}
+	}
+}
+
+/// Encodes a serialized command as request.
+pub fn encode_request(payload: &[u8]) -> Vec<u8> {
+	[payload, REQUEST_TERMINATOR].concat()
+}
+
+/// Whether a serialized command or response is msgpack rather than JSON
+/// encoded, told by its first byte. JSON text starts with printable ASCII or
+/// whitespace, both of which are taken as JSON.
+pub fn is_msgpack(payload: &[u8]) -> bool {
+	match payload.first() {
+		Some(byte) => !(byte.is_ascii_graphic() || byte.is_ascii_whitespace()),
+		None => false,
+	}
+}
+
+/// Splits the first request off `buffer`. Returns `None` if the buffer does not
+/// contain a terminated request yet, otherwise the serialized command and the
+/// number of bytes the request occupied.
+///
+/// Requests are only delimited by their terminator, so this can't tell apart a
+/// terminator from the same bytes inside a msgpack encoded command.
+pub fn decode_request(buffer: &[u8]) -> Option<(Vec<u8>, usize)> {
+	buffer
+		.windows(REQUEST_TERMINATOR.len())
+		.position(|w| w == REQUEST_TERMINATOR)
+		.map(|pos| (buffer[..pos].to_vec(), pos + REQUEST_TERMINATOR.len()))
+}
+
+/// Writes a serialized command as request.
+pub fn write_request<W: Write + ?Sized>(writer: &mut W, payload: &[u8]) -> Result<()> {
+	writer.write_all(&encode_request(payload))?;
+	writer.flush()?;
+
+	Ok(())
+}
+
+/// Blocks until a complete response frame has been read. Frames announcing more
+/// than `max_frame_size` bytes are rejected before anything is allocated.
+pub fn read_frame<R: Read + ?Sized>(
+	reader: &mut R,
+	layout: FrameLayout,
+	max_frame_size: usize,
+) -> Result<Frame> {
+	let mut header = [0; LENGTH_HEADER_SIZE];
+	reader.read_exact(&mut header)?;
+
+	let len = check_length(parse_length_header(&header)?, layout, max_frame_size)?;
+
+	let mut body = vec![0; len];
+	reader.read_exact(&mut body)?;
+
+	split_body(&body, layout)
+}
+
+/// Writes a response frame the way a device does.
+pub fn write_frame<W: Write + ?Sized>(writer: &mut W, frame: &Frame) -> Result<()> {
+	writer.write_all(&frame.encode())?;
+	writer.flush()?;
+
+	Ok(())
+}
+
+/// Sends a request and waits for its response.
+pub fn request<S: Read + Write + ?Sized>(
+	stream: &mut S,
+	payload: &[u8],
+	layout: FrameLayout,
+	max_frame_size: usize,
+) -> Result<Frame> {
+	write_request(stream, payload)?;
+	read_frame(stream, layout, max_frame_size)
+}
+
+/// Async counterpart of [`write_request`].
+#[cfg(feature = "async")]
+pub async fn write_request_async<W: AsyncWrite + Unpin + ?Sized>(
+	writer: &mut W,
+	payload: &[u8],
+) -> Result<()> {
+	writer.write_all(&encode_request(payload)).await?;
+	writer.flush().await?;
+
+	Ok(())
+}
+
+/// Async counterpart of [`read_frame`].
+#[cfg(feature = "async")]
+pub async fn read_frame_async<R: AsyncRead + Unpin + ?Sized>(
+	reader: &mut R,
+	layout: FrameLayout,
+	max_frame_size: usize,
+) -> Result<Frame> {
+	let mut header = [0; LENGTH_HEADER_SIZE];
+	reader.read_exact(&mut header).await?;
+
+	let len = check_length(parse_length_header(&header)?, layout, max_frame_size)?;
+
+	let mut body = vec![0; len];
+	reader.read_exact(&mut body).await?;
+
+	split_body(&body, layout)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	type Decoded = Option<(Frame, usize)>;
+
+	fn framing_error(result: Result<Decoded>) -> Option<FramingError> {
+		match result {
+			Err(BoneError::Framing(e)) => Some(e),
+			_ => None,
+		}
+	}
+
+	#[test]
+	fn decode_frames() {
+		let cases: &[(&[u8], FrameLayout, Decoded)] = &[
+			(b"", FrameLayout::Plain, None),
+			(b"0000000", FrameLayout::Plain, None),
+			(
+				b"00000000",
+				FrameLayout::Plain,
+				Some((Frame::plain(vec![]), 8)),
+			),
+			(b"00000003ab", FrameLayout::Plain, None),
+			(
+				b"00000003abcdef",
+				FrameLayout::Plain,
+				Some((Frame::plain(b"abc".to_vec()), 11)),
+			),
+			(
+				b"0000000A\x00\x00\x01\x00abcdef",
+				FrameLayout::Positioned,
+				Some((Frame::positioned(256, b"abcdef".to_vec()), 18)),
+			),
+			(
+				b"00000004\xff\xff\xff\xfe",
+				FrameLayout::Positioned,
+				Some((Frame::positioned(-2, vec![]), 12)),
+			),
+			(b"00000006\x00\x00\x00\x01a", FrameLayout::Positioned, None),
+		];
+
+		for (buffer, layout, expected) in cases {
+			assert_eq!(
+				Frame::decode(buffer, *layout, DEFAULT_MAX_FRAME_SIZE).unwrap(),
+				*expected,
+				"decoding {:?}",
+				String::from_utf8_lossy(buffer)
+			);
+		}
+	}
+
+	#[test]
+	fn encode_frames() {
+		let cases = [
+			(Frame::plain(vec![]), &b"00000000"[..]),
+			(Frame::plain(b"abc".to_vec()), b"00000003abc"),
+			(
+				Frame::positioned(256, b"ab".to_vec()),
+				b"00000006\x00\x00\x01\x00ab",
+			),
+		];
+
+		for (frame, expected) in cases {
+			assert_eq!(frame.encode(), expected);
+
+			let decoded = Frame::decode(expected, frame.layout(), DEFAULT_MAX_FRAME_SIZE).unwrap();
+			assert_eq!(decoded, Some((frame, expected.len())));
+		}
+	}
+
+	#[test]
+	fn framing_errors() {
+		let cases: &[(&[u8], FrameLayout, usize, Option<FramingError>)] = &[
+			(b"00000010", FrameLayout::Plain, 16, None),
+			(
+				b"00000011",
+				FrameLayout::Plain,
+				16,
+				Some(FramingError::TooLarge { len: 17, max: 16 }),
+			),
+			(
+				b"ffffffff",
+				FrameLayout::Positioned,
+				DEFAULT_MAX_FRAME_SIZE,
+				Some(FramingError::TooLarge {
+					len: 0xffffffff,
+					max: DEFAULT_MAX_FRAME_SIZE,
+				}),
+			),
+			(b"00000004", FrameLayout::Positioned, 16, None),
+			(
+				b"00000003",
+				FrameLayout::Positioned,
+				16,
+				Some(FramingError::TooShort { len: 3 }),
+			),
+			(
+				b"00000000",
+				FrameLayout::Positioned,
+				16,
+				Some(FramingError::TooShort { len: 0 }),
+			),
+			(b"00000003", FrameLayout::Plain, 16, None),
+			(
+				b"0000000g",
+				FrameLayout::Plain,
+				16,
+				Some(FramingError::InvalidHeader(*b"0000000g")),
+			),
+			(
+				b"-0000001",
+				FrameLayout::Plain,
+				16,
+				Some(FramingError::InvalidHeader(*b"-0000001")),
+			),
+			(
+				b"{\"comman",
+				FrameLayout::Plain,
+				16,
+				Some(FramingError::InvalidHeader(*b"{\"comman")),
+			),
+		];
+
+		for (buffer, layout, max_frame_size, expected) in cases {
+			let result = Frame::decode(buffer, *layout, *max_frame_size);
+			assert_eq!(
+				framing_error(result),
+				*expected,
+				"decoding {:?}",
+				String::from_utf8_lossy(buffer)
+			);
+
+			// read_frame applies the same checks before reading the body
+			let mut reader = &buffer[..];
+			match read_frame(&mut reader, *layout, *max_frame_size) {
+				Err(BoneError::Framing(e)) => assert_eq!(Some(e), *expected),
+				_ => assert_eq!(*expected, None),
+			}
+		}
+	}
+
+	#[test]
+	fn requests() {
+		let cases = [
+			(&b""[..], None),
+			(b"{\"command\": \"date\"}", None),
+			(b"{}\r", None),
+			(b"{}\r\n", Some((b"{}".to_vec(), 4))),
+			(b"{}\r\n{\"command\"", Some((b"{}".to_vec(), 4))),
+			(b"\r\n", Some((vec![], 2))),
+		];
+
+		for (buffer, expected) in cases {
+			assert_eq!(decode_request(buffer), expected);
+		}
+
+		assert_eq!(encode_request(b"{}"), b"{}\r\n");
+	}
+}
@@ -0,0 +1,273 @@
+//! Finding devices on the link-local network.
+//!
+//! Devices derive their IPv6 link-local address from their serial number,
+//! `fe80::b5:b1ff:feXX:YY`. Candidates are taken from the IPv6 neighbour table
+//! and, optionally, a range of serial numbers, then asked for their serial
+//! number on every interface with a link-local address. [`serial_from_address`]
+//! maps an address found elsewhere, e.g. in a packet capture, back to the serial number.
+
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+
+use crate::{Bone, BoneError, Result, TlsConfig};
+
+/// Options of [`discover`].
+#[derive(Clone, Debug)]
+pub struct DiscoveryOptions {
+	pub port: String,
+	pub encrypted: bool,
+	/// Serial numbers whose addresses are probed in addition to the neighbour table.
+	pub serials: Option<RangeInclusive<u32>>,
+	/// Connect, read and write timeout of each probe.
+	pub timeout: Duration,
+	/// Number of probes running at the same time.
+	pub parallel: usize,
+}
+
+impl Default for DiscoveryOptions {
+	fn default() -> Self {
+		DiscoveryOptions {
+			port: String::from("6451"),
+			encrypted: true,
+			serials: None,
+			timeout: Duration::from_secs(1),
+			parallel: 64,
+		}
+	}
+}
+
+/// A device that answered during discovery.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DiscoveredDevice {
+	pub interface: String,
+	pub serial_number: String,
+	pub alias: Option<String>,
+	pub address: Ipv6Addr,
+}
+
+impl DiscoveredDevice {
+	/// Address including the zone id, to connect to.
+	pub fn host(&self) -> String {
+		format!("{}%{}", self.address, self.interface)
+	}
+}
+
+/// Link-local address of the device with the given serial number.
+pub fn link_local_address(serial: u32) -> Ipv6Addr {
+	let hex = format!("{:04x}", serial);
+
+	format!("fe80::b5:b1ff:fe{}:{}", &hex[..2], &hex[2..])
+		.parse()
+		.unwrap_or(Ipv6Addr::UNSPECIFIED)
+}
+
+/// Whether `address` has the form of a device link-local address.
+pub fn is_device_address(address: &Ipv6Addr) -> bool {
+	let segments = address.segments();
+
+	segments[..4] == [0xfe80, 0, 0, 0]
+		&& segments[4] == 0x00b5
+		&& segments[5] == 0xb1ff
+		&& segments[6] & 0xff00 == 0xfe00
+}
+
+/// Serial number of the device with the link-local `address`, the inverse of
+/// [`link_local_address`]. `None` if the address does not follow the device
+/// pattern. Addresses shared by several serial numbers map to the smallest one.
+pub fn serial_from_address(address: &Ipv6Addr) -> Option<u32> {
+	if !is_device_address(address) {
+		return None;
+	}
+
+	let segments = address.segments();
+	let (high, low) = (u32::from(segments[6] & 0xff), u32::from(segments[7]));
+
+	// the first two hex digits of the serial number go to the sixth segment,
+	// the remaining two to four to the last one
+	[high << 8 | low, high << 12 | low, high << 16 | low]
+		.into_iter()
+		.find(|&serial| link_local_address(serial) == *address)
+}
+
+/// Serial number of the device at `host`, which may be enclosed in brackets and
+/// carry a zone id. `None` for host names and other addresses.
+pub fn serial_from_host(host: &str) -> Option<u32> {
+	let host = host.trim_start_matches('[').trim_end_matches(']');
+	let address = host.split('%').next()?.parse().ok()?;
+
+	serial_from_address(&address)
+}
+
+/// Names of the non-loopback interfaces with an IPv6 link-local address.
+pub fn link_local_interfaces() -> Result<Vec<String>> {
+	let interfaces = NetworkInterface::show()
+		.map_err(|e| BoneError::Io(std::io::Error::other(e.to_string())))?;
+
+	let mut names: Vec<String> = interfaces
+		.into_iter()
+		.filter(|interface| {
+			interface.addr.iter().any(|addr| match addr.ip() {
+				IpAddr::V6(ip) => !ip.is_loopback() && ip.segments()[0] == 0xfe80,
+				IpAddr::V4(_) => false,
+			})
+		})
+		.map(|interface| interface.name)
+		.collect();
+
+	names.sort();
+	names.dedup();
+
+	Ok(names)
+}
+
+/// Link-local entries of the IPv6 neighbour table, as address and interface.
+/// Only available on Linux, where it is read with `ip -6 neigh`.
+pub fn neighbours() -> Vec<(Ipv6Addr, String)> {
+	if !cfg!(target_os = "linux") {
+		return Vec::new();
+	}
+
+	let output = match std::process::Command::new("ip")
+		.args(["-6", "neigh", "show"])
+		.output()
+	{
+		Ok(output) => output,
+		Err(_) => return Vec::new(),
+	};
+
+	// fe80::b5:b1ff:fe00:1 dev eth0 lladdr 02:b5:b1:00:00:01 REACHABLE
+	String::from_utf8_lossy(&output.stdout)
+		.lines()
+		.filter_map(|line| {
+			let mut fields = line.split_whitespace();
+			let address: Ipv6Addr = fields.next()?.parse().ok()?;
+			let interface = fields.skip_while(|field| *field != "dev").nth(1)?;
+
+			Some((address, interface.to_string()))
+		})
+		.filter(|(address, _)| address.segments()[0] == 0xfe80)
+		.collect()
+}
+
+/// Finds the devices that answer on the link-local network, sorted by
+/// interface and serial number.
+pub fn discover(options: &DiscoveryOptions) -> Result<Vec<DiscoveredDevice>> {
+	let interfaces = link_local_interfaces()?;
+
+	let mut candidates = BTreeSet::new();
+
+	for (address, interface) in neighbours() {
+		if is_device_address(&address) && interfaces.contains(&interface) {
+			candidates.insert((interface, address));
+		}
+	}
+
+	if let Some(ref serials) = options.serials {
+		for interface in &interfaces {
+			for serial in serials.clone() {
+				candidates.insert((interface.clone(), link_local_address(serial)));
+			}
+		}
+	}
+
+	let candidates = Mutex::new(candidates.into_iter());
+	let devices = Mutex::new(Vec::new());
+
+	thread::scope(|scope| {
+		for _ in 0..options.parallel.max(1) {
+			scope.spawn(|| loop {
+				let next = candidates.lock().unwrap().next();
+				let (interface, address) = match next {
+					Some(candidate) => candidate,
+					None => break,
+				};
+
+				if let Some(device) = probe(options, interface, address) {
+					devices.lock().unwrap().push(device);
+				}
+			});
+		}
+	});
+
+	let mut devices = devices.into_inner().unwrap();
+	devices.sort();
+
+	Ok(devices)
+}
+
+/// Connects to the device with `serial` by trying its link-local address on all
+/// `interfaces` in parallel. `connect` opens and checks a connection to the given
+/// host, the first one that succeeds is returned along with its interface.
+/// Slower attempts are left to finish in the background.
+pub fn connect_serial<T, F>(serial: u32, interfaces: &[String], connect: F) -> Result<(T, String)>
+where
+	T: Send + 'static,
+	F: Fn(&str) -> Result<T> + Send + Sync + 'static,
+{
+	let connect = Arc::new(connect);
+	let (tx, rx) = mpsc::channel();
+
+	for interface in interfaces {
+		let (connect, tx) = (connect.clone(), tx.clone());
+		let interface = interface.clone();
+		let host = format!("{}%{}", link_local_address(serial), interface);
+
+		thread::spawn(move || {
+			let _ = tx.send((connect(&host), interface));
+		});
+	}
+
+	drop(tx);
+
+	let mut error = None;
+
+	for (result, interface) in rx {
+		match result {
+			Ok(connection) => return Ok((connection, interface)),
+			// an error of the device itself tells more than interfaces it isn't reachable on
+			Err(e) => match error {
+				Some(BoneError::Io(_) | BoneError::Timeout) | None => error = Some(e),
+				Some(_) => {}
+			},
+		}
+	}
+
+	Err(error.unwrap_or_else(|| {
+		BoneError::Io(std::io::Error::new(
+			std::io::ErrorKind::NotFound,
+			"no interface with a link-local address",
+		))
+	}))
+}
+
+/// Asks the device at `address` for its serial number.
+fn probe(
+	options: &DiscoveryOptions,
+	interface: String,
+	address: Ipv6Addr,
+) -> Option<DiscoveredDevice> {
+	let host = format!("{}%{}", address, interface);
+
+	let mut bone = Bone::new(&host, &options.port, false, options.encrypted);
+	// only the serial number is read, the device is not trusted with anything
+	bone.set_tls_config(TlsConfig::insecure());
+	bone.set_connect_timeout(Some(options.timeout));
+	bone.set_read_timeout(Some(options.timeout)).ok()?;
+	bone.set_write_timeout(Some(options.timeout)).ok()?;
+	bone.connect().ok()?;
+
+	let response = bone.serial_number().ok()?;
+
+	Some(DiscoveredDevice {
+		interface,
+		serial_number: response.serial_number,
+		alias: response.alias,
+		address,
+	})
+}
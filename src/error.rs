@@ -0,0 +1,169 @@
+use std::fmt;
+
+use crate::codec::FramingError;
+
+/// Errors returned by every fallible operation on [`crate::Bone`].
+#[derive(Debug)]
+pub enum BoneError {
+	/// A command was issued before `connect` succeeded.
+	NotConnected,
+	/// Reading from or writing to the underlying stream failed.
+	Io(std::io::Error),
+	/// The device did not answer within the configured timeout.
+	Timeout,
+	/// Setting up or negotiating the TLS session failed.
+	Tls(openssl::ssl::Error),
+	/// The proxy refused or failed to open a tunnel to the device.
+	Proxy(String),
+	/// The device certificate does not match the expected identity.
+	Certificate(String),
+	/// The device certificate differs from the one recorded in the known hosts file.
+	FingerprintMismatch {
+		name: String,
+		known: String,
+		presented: String,
+	},
+	/// The response did not follow the length-prefixed wire format.
+	Framing(FramingError),
+	/// The response body could not be decoded (e.g. invalid UTF-8 or hex).
+	Decode(String),
+	/// A command or response was not valid JSON.
+	Json(String),
+	/// A command or response could not be converted to or from msgpack.
+	Msgpack(String),
+	/// The device rejected the supplied credentials.
+	Auth(String),
+	/// The command requires a login, which is missing or timed out.
+	SessionExpired(String),
+	/// The device answered with an error message.
+	Device { message: String },
+}
+
+pub type Result<T> = std::result::Result<T, BoneError>;
+
+impl fmt::Display for BoneError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			BoneError::NotConnected => write!(f, "not connected"),
+			BoneError::Io(e) => write!(f, "i/o error: {}", e),
+			BoneError::Timeout => write!(f, "timed out"),
+			BoneError::Tls(e) => write!(f, "tls error: {}", e),
+			BoneError::Proxy(msg) => write!(f, "proxy error: {}", msg),
+			BoneError::Certificate(msg) => write!(f, "certificate error: {}", msg),
+			BoneError::FingerprintMismatch {
+				name,
+				known,
+				presented,
+			} => write!(
+				f,
+				"certificate of {} has changed (known {}, presented {})",
+				name, known, presented
+			),
+			BoneError::Framing(e) => write!(f, "framing error: {}", e),
+			BoneError::Decode(msg) => write!(f, "decode error: {}", msg),
+			BoneError::Json(msg) => write!(f, "json error: {}", msg),
+			BoneError::Msgpack(msg) => write!(f, "msgpack error: {}", msg),
+			BoneError::Auth(msg) => write!(f, "authentication failed: {}", msg),
+			BoneError::SessionExpired(msg) => write!(f, "session expired: {}", msg),
+			BoneError::Device { message } => write!(f, "device error: {}", message),
+		}
+	}
+}
+
+impl BoneError {
+	/// Whether connecting failed because the device offers no encryption, e.g. on
+	/// older firmware: the connection was refused, reset or closed before the device
+	/// sent anything. TLS errors never count, since an attacker could provoke them
+	/// to downgrade the connection.
+	pub fn is_tls_unavailable(&self) -> bool {
+		use std::io::ErrorKind;
+
+		match self {
+			BoneError::Io(e) => matches!(
+				e.kind(),
+				ErrorKind::ConnectionRefused
+					| ErrorKind::ConnectionReset
+					| ErrorKind::ConnectionAborted
+					| ErrorKind::UnexpectedEof
+			),
+			_ => false,
+		}
+	}
+}
+
+impl std::error::Error for BoneError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			BoneError::Io(e) => Some(e),
+			BoneError::Tls(e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+impl From<std::io::Error> for BoneError {
+	fn from(e: std::io::Error) -> Self {
+		match e.kind() {
+			// read/write timeouts surface as WouldBlock on unix and TimedOut on windows
+			std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => BoneError::Timeout,
+			_ => BoneError::Io(e),
+		}
+	}
+}
+
+impl From<openssl::ssl::Error> for BoneError {
+	fn from(e: openssl::ssl::Error) -> Self {
+		match e.io_error().map(|e| e.kind()) {
+			Some(std::io::ErrorKind::TimedOut) | Some(std::io::ErrorKind::WouldBlock) => {
+				BoneError::Timeout
+			}
+			_ => BoneError::Tls(e),
+		}
+	}
+}
+
+impl From<openssl::error::ErrorStack> for BoneError {
+	fn from(e: openssl::error::ErrorStack) -> Self {
+		BoneError::Tls(e.into())
+	}
+}
+
+impl<S> From<openssl::ssl::HandshakeError<S>> for BoneError {
+	fn from(e: openssl::ssl::HandshakeError<S>) -> Self {
+		match e {
+			openssl::ssl::HandshakeError::SetupFailure(e) => e.into(),
+			openssl::ssl::HandshakeError::Failure(s) => s.into_error().into(),
+			openssl::ssl::HandshakeError::WouldBlock(s) => s.into_error().into(),
+		}
+	}
+}
+
+impl From<serde_json::Error> for BoneError {
+	fn from(e: serde_json::Error) -> Self {
+		BoneError::Json(e.to_string())
+	}
+}
+
+impl From<rmp_serde::encode::Error> for BoneError {
+	fn from(e: rmp_serde::encode::Error) -> Self {
+		BoneError::Msgpack(e.to_string())
+	}
+}
+
+impl From<rmp_serde::decode::Error> for BoneError {
+	fn from(e: rmp_serde::decode::Error) -> Self {
+		BoneError::Msgpack(e.to_string())
+	}
+}
+
+impl From<rmpv::decode::Error> for BoneError {
+	fn from(e: rmpv::decode::Error) -> Self {
+		BoneError::Msgpack(e.to_string())
+	}
+}
+
+impl From<std::string::FromUtf8Error> for BoneError {
+	fn from(e: std::string::FromUtf8Error) -> Self {
+		BoneError::Decode(e.to_string())
+	}
+}
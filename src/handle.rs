@@ -0,0 +1,110 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::models::Request;
+use crate::{msgpack, Bone, RawData, Result};
+
+/// Cheaply cloneable handle sharing one [`Bone`] between threads. Requests from
+/// all clones are serialized, each one waits for the previous response.
+///
+/// ```no_run
+/// use bone_api::{Bone, BoneHandle};
+/// use serde_json::json;
+///
+/// let mut bone = Bone::new("192.168.1.10", "6450", false, false);
+/// bone.connect().unwrap();
+///
+/// let handle = BoneHandle::new(bone);
+/// let worker = handle.clone();
+///
+/// std::thread::spawn(move || worker.send_command(&json!({"command": "date"})));
+/// handle.send_command(&json!({"command": "serial_number"})).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct BoneHandle {
+	bone: Arc<Mutex<Bone>>,
+}
+
+impl BoneHandle {
+	pub fn new(bone: Bone) -> BoneHandle {
+		BoneHandle {
+			bone: Arc::new(Mutex::new(bone)),
+		}
+	}
+
+	/// Exclusive access to the underlying client, e.g. to change its settings or
+	/// to send several commands without other requests in between.
+	pub fn lock(&self) -> MutexGuard<'_, Bone> {
+		self.bone.lock().unwrap_or_else(|poisoned| {
+			// a thread panicked mid request, the stream position is unknown
+			let mut bone = poisoned.into_inner();
+			bone.disconnect();
+			self.bone.clear_poison();
+			bone
+		})
+	}
+
+	pub fn connect(&self) -> Result<()> {
+		self.lock().connect()
+	}
+
+	pub fn is_connected(&self) -> bool {
+		self.lock().is_connected()
+	}
+
+	pub fn disconnect(&self) {
+		self.lock().disconnect()
+	}
+
+	pub fn send_raw_command(&self, command: &Value) -> Result<(i32, Vec<u8>)> {
+		self.lock().send_raw_command(command)
+	}
+
+	pub fn send_sync_command(&self, command: &Value) -> Result<(i32, RawData)> {
+		self.lock().send_sync_command(command)
+	}
+
+	pub fn send_ks_command(&self, command: &Value) -> Result<(i32, RawData)> {
+		self.lock().send_ks_command(command)
+	}
+
+	pub fn send_ks_sync_command(&self, command: &Value) -> Result<(i32, RawData)> {
+		self.lock().send_ks_sync_command(command)
+	}
+
+	pub fn send_dv_command(&self, command: &Value) -> Result<Vec<f32>> {
+		self.lock().send_dv_command(command)
+	}
+
+	pub fn send_command(&self, command: &Value) -> Result<Value> {
+		self.lock().send_command(command)
+	}
+
+	pub fn send_command_msgpack(&self, command: &Value) -> Result<msgpack::Value> {
+		self.lock().send_command_msgpack(command)
+	}
+
+	pub fn send_batch(&self, commands: &[Value]) -> Vec<Result<Value>> {
+		self.lock().send_batch(commands)
+	}
+
+	pub fn send_batch_msgpack(&self, commands: &[Value]) -> Vec<Result<msgpack::Value>> {
+		self.lock().send_batch_msgpack(commands)
+	}
+
+	pub fn call<Req: Request, Resp: DeserializeOwned>(&self, request: &Req) -> Result<Resp> {
+		self.lock().call(request)
+	}
+
+	pub fn login(&self, username: &str, password: &str) -> Result<String> {
+		self.lock().login(username, password)
+	}
+}
+
+impl From<Bone> for BoneHandle {
+	fn from(bone: Bone) -> Self {
+		BoneHandle::new(bone)
+	}
+}
@@ -0,0 +1,155 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use openssl::hash::MessageDigest;
+use openssl::x509::X509Ref;
+
+use crate::Result;
+
+// keeps the temporary files of concurrent saves apart
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Certificate fingerprints of devices that have been connected to before,
+/// stored one `name fingerprint` pair per line.
+#[derive(Clone, Debug)]
+pub struct KnownHosts {
+	path: PathBuf,
+	entries: Vec<(String, String)>,
+	// the file as it was read, so comments survive a save
+	lines: Vec<String>,
+}
+
+impl KnownHosts {
+	/// `~/.bone_shell_known_hosts`, if a home directory can be found.
+	pub fn default_path() -> Option<PathBuf> {
+		dirs::home_dir().map(|home| home.join(".bone_shell_known_hosts"))
+	}
+
+	/// Reads the file at `path`. A missing file is treated as empty.
+	pub fn load(path: &Path) -> Result<KnownHosts> {
+		let content = match fs::read_to_string(path) {
+			Ok(content) => content,
+			Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+			Err(e) => return Err(e.into()),
+		};
+
+		let entries = content
+			.lines()
+			.filter_map(parse_entry)
+			.map(|(name, fingerprint)| (name.to_string(), fingerprint.to_string()))
+			.collect();
+
+		Ok(KnownHosts {
+			path: path.to_path_buf(),
+			entries,
+			lines: content.lines().map(String::from).collect(),
+		})
+	}
+
+	/// Writes the entries back, keeping comments and the order of the file. The
+	/// file is replaced atomically, so a concurrent reader never sees it half written.
+	pub fn save(&self) -> Result<()> {
+		let mut written = HashSet::new();
+		let mut content = String::new();
+
+		for line in &self.lines {
+			match parse_entry(line) {
+				Some((name, _)) => {
+					if let Some(fingerprint) = self.get(name) {
+						if written.insert(name) {
+							content.push_str(&format!("{} {}\n", name, fingerprint));
+						}
+					}
+				}
+				None => {
+					content.push_str(line);
+					content.push('\n');
+				}
+			}
+		}
+
+		for (name, fingerprint) in &self.entries {
+			if !written.contains(name.as_str()) {
+				content.push_str(&format!("{} {}\n", name, fingerprint));
+			}
+		}
+
+		let file_name = self
+			.path
+			.file_name()
+			.map(|name| name.to_string_lossy().into_owned())
+			.unwrap_or_default();
+		let temp = self.path.with_file_name(format!(
+			".{}.{}.{}.tmp",
+			file_name,
+			std::process::id(),
+			TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+		));
+
+		let result = fs::File::create(&temp)
+			.and_then(|mut file| {
+				file.write_all(content.as_bytes())?;
+				file.sync_all()
+			})
+			.and_then(|_| fs::rename(&temp, &self.path));
+
+		if result.is_err() {
+			let _ = fs::remove_file(&temp);
+		}
+
+		Ok(result?)
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	pub fn entries(&self) -> &[(String, String)] {
+		&self.entries
+	}
+
+	pub fn get(&self, name: &str) -> Option<&str> {
+		self.entries
+			.iter()
+			.find(|(n, _)| n == name)
+			.map(|(_, fingerprint)| fingerprint.as_str())
+	}
+
+	/// Adds or replaces the fingerprint stored for `name`.
+	pub fn insert(&mut self, name: &str, fingerprint: &str) {
+		match self.entries.iter_mut().find(|(n, _)| n == name) {
+			Some(entry) => entry.1 = fingerprint.to_string(),
+			None => self
+				.entries
+				.push((name.to_string(), fingerprint.to_string())),
+		}
+	}
+
+	/// Removes the entry for `name`, returns whether there was one.
+	pub fn remove(&mut self, name: &str) -> bool {
+		let len = self.entries.len();
+		self.entries.retain(|(n, _)| n != name);
+		len != self.entries.len()
+	}
+}
+
+/// Splits a `name fingerprint` line, `None` for comments and blank lines.
+fn parse_entry(line: &str) -> Option<(&str, &str)> {
+	let line = line.trim();
+
+	if line.is_empty() || line.starts_with('#') {
+		return None;
+	}
+
+	line.split_once(char::is_whitespace)
+		.map(|(name, fingerprint)| (name, fingerprint.trim()))
+}
+
+/// SHA-256 fingerprint of a certificate in the format stored in known hosts files.
+pub fn fingerprint(cert: &X509Ref) -> Result<String> {
+	let digest = cert.digest(MessageDigest::sha256())?;
+	Ok(format!("sha256:{}", hex::encode(digest)))
+}
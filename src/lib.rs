@@ -1,127 +1,200 @@
-use std::io::Error;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
 
 use openssl::sha::sha512;
-use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
 
-use serde_json::Value;
-
-trait IsStream: Read + Write {}
-impl<T: Read + Write> IsStream for T {}
+use serde_json::{json, Value};
+
+#[cfg(feature = "async")]
+mod async_bone;
+pub mod codec;
+pub mod discovery;
+mod error;
+mod handle;
+pub mod known_hosts;
+pub mod models;
+pub mod msgpack;
+pub mod proxy;
+mod raw;
+mod reconnect;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod tls;
+pub mod transport;
+
+#[cfg(feature = "async")]
+pub use async_bone::AsyncBone;
+use codec::{Frame, FrameLayout};
+pub use error::{BoneError, Result};
+pub use handle::BoneHandle;
+pub use reconnect::{ReconnectEvent, ReconnectPolicy};
+pub use tls::{ClientIdentity, PeerTrust, TlsConfig, TlsIdentity};
+
+/// Decoded raw data as a list of (channel name, samples) pairs.
+pub type RawData = Vec<(String, Vec<f32>)>;
+
+use proxy::{Proxy, ProxyTransport};
+use reconnect::ReconnectHandler;
+use transport::{Stream, TcpTransport, Timeouts, Transport};
 
 pub struct Bone {
 	ip: String,
 	port: String,
-	stream: Option<Box<dyn IsStream>>,
+	transport: Option<Box<dyn Transport>>,
+	stream: Option<Box<dyn Stream>>,
 	enable_msgpack: bool,
 	use_ssl: bool,
+	tls_config: TlsConfig,
+	peer_trust: Option<PeerTrust>,
+	peer_fingerprint: Option<String>,
+	max_frame_size: usize,
+	/// Commands of a batch sent ahead of their responses.
+	pipeline_depth: usize,
+	timeouts: Timeouts,
+	keepalive: Option<Duration>,
+	reconnect_policy: Option<ReconnectPolicy>,
+	reconnect_handler: Option<ReconnectHandler>,
+	/// Whether the connection is to be kept open, until `disconnect` is called.
+	keep_connected: bool,
+	/// User name and password of the last login, replayed after reconnecting.
+	credentials: Option<(String, String)>,
 }
 
 impl Bone {
-	fn get_connection_string(&self) -> String {
-		let mut connect_str = String::from(&self.ip);
-		connect_str.push_str(":");
-		connect_str.push_str(&self.port);
-		connect_str
-	}
-
 	fn get_sha512_string(input_str: &str) -> String {
 		let hash = sha512(input_str.as_bytes());
-		let hash_str = hex::encode(hash);
-		format!("{}", hash_str)
+		hex::encode(hash)
 	}
 
-	fn get_signed_token(password: &str, token: &str) -> String {
-		let password_hashed = Bone::get_sha512_string(&password);
+	pub(crate) fn get_signed_token(password: &str, token: &str) -> String {
+		let password_hashed = Bone::get_sha512_string(password);
 
 		let mut concat = String::from(&password_hashed);
-		concat.push_str(&token);
+		concat.push_str(token);
 
 		Bone::get_sha512_string(&concat)
 	}
 
-	fn calc_saw(buffer: &Vec<u8>, output_vec: &mut Vec<(String, Vec<f32>)>) {
-		let mut rt_buf: Vec<f32> = Vec::new();
-		let mut amp_buf: Vec<f32> = Vec::new();
-
-		for i in (0..buffer.len()).step_by(4) {
-			let data: u32 = buffer[i + 3] as u32
-				+ ((buffer[i + 2] as u32) << 8)
-				+ ((buffer[i + 1] as u32) << 16)
-				+ ((buffer[i] as u32) << 24);
+	fn send_frame(&mut self, command: &Value, layout: FrameLayout) -> Result<Frame> {
+		let reconnect = self.keep_connected && self.reconnect_policy.is_some();
 
-			let mut runtime: f32 = ((data & 0xfffff000) >> 12) as f32;
-			runtime /= 521.0;
-			runtime *= 100.0;
+		// an earlier reconnect gave up
+		if reconnect && self.stream.is_none() {
+			self.reconnect(None)?;
+		}
 
-			let mut amplitude: f32 = (data & 0x00000fff) as f32;
+		let result = self.exchange(command, layout);
 
-			amplitude /= 4096.0;
-			amplitude *= 5.0;
-			amplitude -= 2.5;
-			amplitude *= 2.0;
+		match result {
+			Err(ref e) if reconnect && self.stream.is_none() => {
+				self.reconnect(Some(e))?;
 
-			rt_buf.push(runtime);
-			amp_buf.push(amplitude);
+				match self.reconnect_policy {
+					Some(ref policy) if policy.is_idempotent(command) => {
+						self.exchange(command, layout)
+					}
+					_ => result,
+				}
+			}
+			result => result,
 		}
-
-		output_vec.push(("rt".to_string(), rt_buf));
-		output_vec.push(("amp".to_string(), amp_buf));
 	}
 
-	fn calc_f32(buffer: &Vec<u8>, output_vec: &mut Vec<(String, Vec<f32>)>, name: &str) {
-		let mut temp: Vec<f32> = Vec::new();
+	/// Sends `command` once and reads the response.
+	fn exchange(&mut self, command: &Value, layout: FrameLayout) -> Result<Frame> {
+		let send_data = encode_command(command, self.enable_msgpack)?;
+		let max_frame_size = self.max_frame_size;
 
-		for i in (0..buffer.len()).step_by(4) {
-			let data: u32 = buffer[i + 3] as u32
-				+ ((buffer[i + 2] as u32) << 8)
-				+ ((buffer[i + 1] as u32) << 16)
-				+ ((buffer[i] as u32) << 24);
-
-			let float: f32 = unsafe { std::mem::transmute(data) };
-
-			temp.push(float);
-		}
-
-		output_vec.push((name.to_string(), temp));
+		self.with_stream(|stream| codec::request(stream, &send_data, layout, max_frame_size))
 	}
 
-	fn calc_f32_ks_sync(buffer: &Vec<u8>, output_vec: &mut Vec<(String, Vec<f32>)>) {
-		let mut temp: [Vec<f32>; 8] = Default::default();
-
-		for i in (0..buffer.len()).step_by(5) {
-			let channel: usize = buffer[i] as usize;
-			let data: u32 = buffer[i + 4] as u32
-				+ ((buffer[i + 3] as u32) << 8)
-				+ ((buffer[i + 2] as u32) << 16)
-				+ ((buffer[i + 1] as u32) << 24);
+	/// Sends `commands` ahead of reading their responses, keeping at most
+	/// `pipeline_depth` of them outstanding. Commands after a transport error
+	/// fail with [`BoneError::NotConnected`].
+	fn exchange_batch(&mut self, commands: &[Value]) -> Vec<Result<Frame>> {
+		let reconnect = self.keep_connected && self.reconnect_policy.is_some();
+
+		if reconnect && self.stream.is_none() && !commands.is_empty() {
+			if let Err(e) = self.reconnect(None) {
+				let mut results = vec![Err(e)];
+				results.extend(
+					commands
+						.iter()
+						.skip(1)
+						.map(|_| Err(BoneError::NotConnected)),
+				);
+				return results;
+			}
+		}
 
-			let float: f32 = unsafe { std::mem::transmute(data) };
+		let mut results: Vec<Option<Result<Frame>>> = commands.iter().map(|_| None).collect();
+		let mut pending = VecDeque::new();
+		let mut next = 0;
+
+		while next < commands.len() || !pending.is_empty() {
+			if next < commands.len() && pending.len() < self.pipeline_depth {
+				let i = next;
+				next += 1;
+
+				// their responses have other layouts and no JSON body
+				if raw::is_raw_command(&commands[i]) {
+					results[i] = Some(Err(BoneError::Decode(format!(
+						"raw data command {} can't be sent in a batch",
+						commands[i]["command"]
+					))));
+					continue;
+				}
 
-			temp[channel].push(float);
-		}
+				let send_data = match encode_command(&commands[i], self.enable_msgpack) {
+					Ok(send_data) => send_data,
+					Err(e) => {
+						results[i] = Some(Err(e));
+						continue;
+					}
+				};
 
-		let mut i = 0;
-		for x in &temp {
-			if x.len() > 0 {
-				output_vec.push((format!("channel {}", i), x.clone()));
+				match self.with_stream(|stream| codec::write_request(stream, &send_data)) {
+					Ok(()) => pending.push_back(i),
+					Err(e) => {
+						results[i] = Some(Err(e));
+						break;
+					}
+				}
+			} else if let Some(i) = pending.pop_front() {
+				let max_frame_size = self.max_frame_size;
+				results[i] = Some(self.with_stream(|stream| {
+					codec::read_frame(stream, FrameLayout::Plain, max_frame_size)
+				}));
+
+				if self.stream.is_none() {
+					break;
+				}
 			}
-
-			i += 1;
 		}
+
+		results
+			.into_iter()
+			.map(|result| result.unwrap_or(Err(BoneError::NotConnected)))
+			.collect()
 	}
 
-	fn calc_dv(buffer: &Vec<u8>) -> Vec<f32> {
-		let mut out = Vec::new();
-		for i in (0..buffer.len()).step_by(3) {
-			let s = String::from_utf8(buffer[i..i + 3].to_vec()).unwrap();
-			let dv = usize::from_str_radix(&s, 16).unwrap();
-			let dv = (dv as f32 - 2048.) / 4096. * 5.;
-			out.push(dv);
+	/// Runs `op` on the stream, which is dropped after errors that leave its
+	/// position unknown.
+	fn with_stream<T>(&mut self, op: impl FnOnce(&mut dyn Stream) -> Result<T>) -> Result<T> {
+		let stream = self.stream.as_mut().ok_or(BoneError::NotConnected)?;
+
+		let result = op(stream.as_mut());
+
+		// after a transport or framing error the stream position is unknown
+		if let Err(
+			BoneError::Io(_) | BoneError::Timeout | BoneError::Framing(_) | BoneError::Tls(_),
+		) = result
+		{
+			self.stream = None;
 		}
 
-		out
+		result
 	}
 
 	pub fn new(ip: &str, port: &str, enable_msgpack: bool, use_ssl: bool) -> Bone {
@@ -129,289 +202,423 @@ impl Bone {
 			ip: ip.to_string(),
 			port: port.to_string(),
 			stream: None,
+			transport: None,
 			enable_msgpack,
 			use_ssl,
+			tls_config: TlsConfig::default(),
+			peer_trust: None,
+			peer_fingerprint: None,
+			max_frame_size: codec::DEFAULT_MAX_FRAME_SIZE,
+			pipeline_depth: 32,
+			timeouts: Timeouts::default(),
+			keepalive: None,
+			reconnect_policy: None,
+			reconnect_handler: None,
+			keep_connected: false,
+			credentials: None,
 		}
 	}
 
-	pub fn connect(&mut self) -> Result<(), Error> {
-		let stream = TcpStream::connect(&self.get_connection_string())?;
+	/// Reconnects automatically when the connection breaks and replays the last
+	/// login. `None`, the default, leaves reconnecting to the caller.
+	pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+		self.reconnect_policy = policy;
+	}
 
-		if self.use_ssl {
-			let mut ssl_ctx_builder = SslConnector::builder(SslMethod::tls()).unwrap();
+	/// Called with the progress of automatic reconnects, e.g. to inform the user.
+	pub fn set_reconnect_handler(&mut self, handler: impl FnMut(&ReconnectEvent) + Send + 'static) {
+		self.reconnect_handler = Some(Box::new(handler));
+	}
 
-			ssl_ctx_builder.set_verify(SslVerifyMode::empty());
+	/// Sends the following commands msgpack instead of JSON encoded. Responses
+	/// are decoded in the encoding they arrive in.
+	pub fn set_msgpack(&mut self, enable_msgpack: bool) {
+		self.enable_msgpack = enable_msgpack;
+	}
 
-			let ssl_ctx = ssl_ctx_builder.build();
+	/// Whether commands are sent msgpack encoded.
+	pub fn msgpack(&self) -> bool {
+		self.enable_msgpack
+	}
 
-			self.stream = Some(Box::new(
-				ssl_ctx
-					.connect(&self.get_connection_string(), stream)
-					.unwrap(),
-			));
-		} else {
-			self.stream = Some(Box::new(stream));
+	/// Sets how the device certificate is verified on encrypted connections.
+	pub fn set_tls_config(&mut self, tls_config: TlsConfig) {
+		self.tls_config = tls_config;
+	}
+
+	/// Name the device is stored under in the known hosts file.
+	pub fn known_hosts_name(&self) -> String {
+		self.tls_config.known_hosts_name(&self.ip, &self.port)
+	}
+
+	/// How the certificate of the current encrypted connection was trusted.
+	pub fn peer_trust(&self) -> Option<PeerTrust> {
+		self.peer_trust
+	}
+
+	/// SHA-256 fingerprint of the certificate of the current encrypted connection.
+	pub fn peer_fingerprint(&self) -> Option<&str> {
+		self.peer_fingerprint.as_deref()
+	}
+
+	/// Adds the device of the current connection to the known hosts file if it was
+	/// accepted on first use with [`TlsConfig::pin_first_use`] disabled.
+	pub fn pin_peer(&self) -> Result<()> {
+		match (self.peer_trust, &self.peer_fingerprint) {
+			(Some(PeerTrust::FirstUse), Some(fingerprint)) => self
+				.tls_config
+				.pin_peer(&self.known_hosts_name(), fingerprint),
+			_ => Ok(()),
 		}
+	}
 
-		Ok(())
+	/// Largest response accepted before the connection is considered broken.
+	/// Defaults to [`codec::DEFAULT_MAX_FRAME_SIZE`].
+	pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+		self.max_frame_size = max_frame_size;
 	}
 
-	pub fn send_raw_command(
-		&mut self,
-		command: &json::JsonValue,
-	) -> Result<(i32, Vec<u8>), String> {
-		if let Some(ref mut stream) = self.stream {
-			let send_data;
-
-			if !self.enable_msgpack {
-				let s = String::from(command.dump());
-				send_data = s.as_bytes().to_vec();
-			} else {
-				let command: Value = match serde_json::from_str(&command.dump()) {
-					Ok(n) => n,
-					Err(err) => return Err(err.to_string()),
-				};
-				send_data = rmp_serde::to_vec(&command).unwrap();
-			}
+	/// Most commands of [`Bone::send_batch`] waiting for their response at a time.
+	/// Defaults to 32, deeper pipelines risk both sides blocking on full buffers.
+	pub fn set_pipeline_depth(&mut self, depth: usize) {
+		self.pipeline_depth = depth.max(1);
+	}
 
-			let send_data = [&send_data[..], "\r\n".as_bytes()].concat();
+	/// Limits how long `connect` waits for the TCP connection to be established.
+	pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+		self.timeouts.connect = timeout;
+	}
 
-			let mut pos = 0;
-			while pos < send_data.len() {
-				let bytes_written = stream.write(&send_data[pos..]).unwrap();
-				pos += bytes_written;
-			}
+	/// Limits how long a single read may block. Applies to the current
+	/// connection as well as to later ones.
+	pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+		self.timeouts.read = timeout;
+
+		if let Some(ref stream) = self.stream {
+			stream.set_read_timeout(timeout)?;
+		}
 
-			let mut buffer = [0; 8];
-			stream.read_exact(&mut buffer).unwrap();
+		Ok(())
+	}
 
-			let s = String::from_utf8(buffer.to_vec()).unwrap();
-			let response_len = usize::from_str_radix(&s, 16).unwrap();
+	/// Limits how long a single write may block. Applies to the current
+	/// connection as well as to later ones.
+	pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+		self.timeouts.write = timeout;
 
-			let mut last_position = [0; 4];
-			stream.read_exact(&mut last_position).unwrap();
+		if let Some(ref stream) = self.stream {
+			stream.set_write_timeout(timeout)?;
+		}
 
-			let last_position: i32 = last_position[3] as i32
-				+ ((last_position[2] as i32) << 8)
-				+ ((last_position[1] as i32) << 16)
-				+ ((last_position[0] as i32) << 24);
+		Ok(())
+	}
 
-			let response_len = response_len - 4;
+	/// Keeps idle connections alive, e.g. through firewalls, by sending TCP
+	/// keepalive probes after `time` without traffic. Transports other than TCP
+	/// ignore it. Applies to the current connection as well as to later ones.
+	pub fn set_keepalive(&mut self, time: Option<Duration>) -> Result<()> {
+		self.keepalive = time;
 
-			let mut buffer = vec![0; response_len];
-			let mut t = 0;
+		if let Some(ref stream) = self.stream {
+			stream.set_keepalive(time)?;
+		}
 
-			while t < response_len {
-				let size = stream.read(&mut buffer[t..]).unwrap();
-				t += size;
-			}
+		Ok(())
+	}
+
+	/// Replaces TCP with another way of reaching the device. Encryption is
+	/// still layered on top when enabled.
+	pub fn set_transport(&mut self, transport: impl Transport + 'static) {
+		self.transport = Some(Box::new(transport));
+	}
+
+	/// Tunnels the TCP connection through `proxy`, replacing any other transport.
+	pub fn set_proxy(&mut self, proxy: Proxy) {
+		self.set_transport(ProxyTransport::new(proxy, &self.ip, &self.port));
+	}
+
+	pub fn connect(&mut self) -> Result<()> {
+		let connection = match self.transport {
+			Some(ref transport) => transport.connect(&self.timeouts)?,
+			None => TcpTransport::new(&self.ip, &self.port).connect(&self.timeouts)?,
+		};
 
-			Ok((last_position, buffer))
+		let connection = if self.use_ssl {
+			self.tls_config
+				.handshake(connection.stream, &self.ip, &self.known_hosts_name())?
 		} else {
-			panic!("Not connected");
+			connection
+		};
+
+		if self.keepalive.is_some() {
+			connection.stream.set_keepalive(self.keepalive)?;
 		}
+
+		self.peer_fingerprint = connection.peer_fingerprint;
+		self.peer_trust = connection.peer_trust;
+		self.stream = Some(connection.stream);
+		self.keep_connected = true;
+
+		Ok(())
+	}
+
+	/// Whether a connection is established. Turns false after the connection
+	/// broke, until `connect` is called again.
+	pub fn is_connected(&self) -> bool {
+		self.stream.is_some()
 	}
 
-	pub fn send_sync_command(
-		&mut self,
-		command: &json::JsonValue,
-	) -> Result<(i32, Vec<(String, Vec<f32>)>), String> {
-		let mut filter: Vec<String> = Vec::new();
+	/// Closes the connection, it is not reopened automatically afterwards.
+	pub fn disconnect(&mut self) {
+		self.stream = None;
+		self.keep_connected = false;
+		self.credentials = None;
+	}
 
-		if command["payload"]["filter"].is_array() {
-			for a in command["payload"]["filter"].members() {
-				filter.push(a.to_string());
+	/// Reopens the broken connection following the reconnect policy and replays
+	/// the last login.
+	fn reconnect(&mut self, cause: Option<&BoneError>) -> Result<()> {
+		let policy = self
+			.reconnect_policy
+			.clone()
+			.ok_or(BoneError::NotConnected)?;
+
+		if let Some(cause) = cause {
+			self.notify(&ReconnectEvent::Lost(cause));
+		}
+
+		let mut attempt = 1;
+
+		loop {
+			match self.connect() {
+				Ok(()) => break,
+				// certificate errors won't go away by retrying
+				Err(e @ (BoneError::Io(_) | BoneError::Timeout | BoneError::Tls(_)))
+					if attempt < policy.max_attempts =>
+				{
+					let delay = policy.backoff(attempt);
+					self.notify(&ReconnectEvent::Retrying {
+						attempt,
+						delay,
+						error: &e,
+					});
+
+					thread::sleep(delay);
+					attempt += 1;
+				}
+				Err(e) => {
+					self.notify(&ReconnectEvent::GaveUp(&e));
+					return Err(e);
+				}
 			}
-		} else {
-			filter = vec![
-				String::from("saw"),
-				String::from("int2"),
-				String::from("coe"),
-				String::from("int"),
-			];
 		}
 
-		let (last_position, buffer) = self.send_raw_command(command).unwrap();
+		self.notify(&ReconnectEvent::Reconnected { attempts: attempt });
+
+		if let Some((username, password)) = self.credentials.clone() {
+			let response =
+				self.exchange(&json!({"command": "request_token"}), FrameLayout::Plain)?;
+			let response = decode_response(response.payload)?;
 
-		let split_val = buffer.len() / filter.len();
-		let mut pos = 0;
-		let mut ret_vect = Vec::new();
+			let command = auth_command(&response, &username, &password)?;
+			let response = self.exchange(&command, FrameLayout::Plain)?;
 
-		for current in filter {
-			match &current[..] {
-				"saw" => Bone::calc_saw(&buffer[pos..pos + split_val].to_vec(), &mut ret_vect),
-				_ => Bone::calc_f32(
-					&buffer[pos..pos + split_val].to_vec(),
-					&mut ret_vect,
-					&current,
-				),
+			if let Err(e) = auth_result(&decode_response(response.payload)?) {
+				self.credentials = None;
+				return Err(e);
 			}
 
-			pos += split_val;
+			self.notify(&ReconnectEvent::Reauthenticated {
+				username: &username,
+			});
 		}
 
-		Ok((last_position, ret_vect))
+		Ok(())
 	}
 
-	pub fn send_ks_command(
-		&mut self,
-		command: &json::JsonValue,
-	) -> Result<(i32, Vec<(String, Vec<f32>)>), String> {
-		let mut command = command.clone();
-		command["payload"]["float"] = true.into();
-
-		let channel: i32;
-		if command["payload"]["channel"].is_number() {
-			channel = command["payload"]["channel"].as_i32().unwrap();
-		} else {
-			channel = 0;
+	fn notify(&mut self, event: &ReconnectEvent) {
+		if let Some(ref mut handler) = self.reconnect_handler {
+			handler(event);
 		}
+	}
 
-		let (last_position, buffer) = self.send_raw_command(&command).unwrap();
+	pub fn send_raw_command(&mut self, command: &Value) -> Result<(i32, Vec<u8>)> {
+		let frame = self.send_frame(command, FrameLayout::Positioned)?;
 
-		let mut ret_vect = Vec::new();
+		Ok((frame.position.unwrap_or_default(), frame.payload))
+	}
 
-		Bone::calc_f32(&buffer, &mut ret_vect, &format!("channel {}", channel));
+	pub fn send_sync_command(&mut self, command: &Value) -> Result<(i32, RawData)> {
+		let filter = raw::sync_filter(command)?;
 
-		Ok((last_position, ret_vect))
-	}
+		let (last_position, buffer) = self.send_raw_command(command)?;
 
-	pub fn send_ks_sync_command(
-		&mut self,
-		command: &json::JsonValue,
-	) -> Result<(i32, Vec<(String, Vec<f32>)>), String> {
-		let (last_position, buffer) = self.send_raw_command(command).unwrap();
+		Ok((last_position, raw::decode_sync(&filter, &buffer)))
+	}
 
-		let mut ret_vect = Vec::new();
+	pub fn send_ks_command(&mut self, command: &Value) -> Result<(i32, RawData)> {
+		let (command, channel) = raw::ks_command(command);
 
-		Bone::calc_f32_ks_sync(&buffer, &mut ret_vect);
+		let (last_position, buffer) = self.send_raw_command(&command)?;
 
-		Ok((last_position, ret_vect))
+		Ok((last_position, raw::decode_ks(channel, &buffer)))
 	}
 
-	pub fn send_dv_command(&mut self, command: &json::JsonValue) -> Result<Vec<f32>, String> {
-		if let Some(ref mut stream) = self.stream {
-			let send_data;
+	pub fn send_ks_sync_command(&mut self, command: &Value) -> Result<(i32, RawData)> {
+		let (last_position, buffer) = self.send_raw_command(command)?;
 
-			if !self.enable_msgpack {
-				let s = String::from(command.dump());
-				send_data = s.as_bytes().to_vec();
-			} else {
-				let command: Value = match serde_json::from_str(&command.dump()) {
-					Ok(n) => n,
-					Err(err) => return Err(err.to_string()),
-				};
-				send_data = rmp_serde::to_vec(&command).unwrap();
-			}
-
-			let send_data = [&send_data[..], "\r\n".as_bytes()].concat();
+		Ok((last_position, raw::decode_ks_sync(&buffer)?))
+	}
 
-			let mut pos = 0;
-			while pos < send_data.len() {
-				let bytes_written = stream.write(&send_data[pos..]).unwrap();
-				pos += bytes_written;
-			}
+	pub fn send_dv_command(&mut self, command: &Value) -> Result<Vec<f32>> {
+		let frame = self.send_frame(command, FrameLayout::Plain)?;
 
-			let mut buffer = [0; 8];
-			stream.read_exact(&mut buffer).unwrap();
+		raw::decode_dv(&frame.payload)
+	}
 
-			let s = String::from_utf8(buffer.to_vec()).unwrap();
-			let response_len = usize::from_str_radix(&s, 16).unwrap();
+	pub fn send_command(&mut self, command: &Value) -> Result<Value> {
+		let buffer = self.send_frame(command, FrameLayout::Plain)?.payload;
+		let response = decode_response(buffer)?;
 
-			let mut buffer = vec![0; response_len];
-			let mut t = 0;
+		check_session(response["payload"]["error"].as_str())?;
+		Ok(response)
+	}
 
-			while t < response_len {
-				let size = stream.read(&mut buffer[t..]).unwrap();
-				t += size;
-			}
+	/// Like [`Bone::send_command`], but keeps binary data, ext types and
+	/// non-finite floats of msgpack responses. JSON responses are converted.
+	pub fn send_command_msgpack(&mut self, command: &Value) -> Result<msgpack::Value> {
+		let buffer = self.send_frame(command, FrameLayout::Plain)?.payload;
+		let response = decode_msgpack_response(buffer)?;
 
-			Ok(Bone::calc_dv(&buffer))
-		} else {
-			panic!("Not connected");
-		}
+		check_session(response["payload"]["error"].as_str())?;
+		Ok(response)
 	}
 
-	pub fn send_command(&mut self, command: &json::JsonValue) -> Result<json::JsonValue, String> {
-		if let Some(ref mut stream) = self.stream {
-			let send_data;
-			if !self.enable_msgpack {
-				let s = String::from(command.dump());
-				send_data = s.as_bytes().to_vec();
-			} else {
-				let command: Value = match serde_json::from_str(&command.dump()) {
-					Ok(n) => n,
-					Err(err) => return Err(err.to_string()),
-				};
-				send_data = rmp_serde::to_vec(&command).unwrap();
-			}
+	/// Sends all `commands` before reading their responses, saving a round trip
+	/// per command on devices that answer several outstanding commands in order.
+	/// Results are in the order of `commands`. Commands are not repeated after
+	/// reconnecting, those after a broken connection fail. Raw data commands like
+	/// `sync` fail without being sent.
+	pub fn send_batch(&mut self, commands: &[Value]) -> Vec<Result<Value>> {
+		self.exchange_batch(commands)
+			.into_iter()
+			.map(|frame| {
+				let response = decode_response(frame?.payload)?;
+
+				check_session(response["payload"]["error"].as_str())?;
+				Ok(response)
+			})
+			.collect()
+	}
 
-			let send_data = [&send_data[..], "\r\n".as_bytes()].concat();
+	/// [`Bone::send_batch`] keeping the msgpack types of the responses, see
+	/// [`Bone::send_command_msgpack`].
+	pub fn send_batch_msgpack(&mut self, commands: &[Value]) -> Vec<Result<msgpack::Value>> {
+		self.exchange_batch(commands)
+			.into_iter()
+			.map(|frame| {
+				let response = decode_msgpack_response(frame?.payload)?;
+
+				check_session(response["payload"]["error"].as_str())?;
+				Ok(response)
+			})
+			.collect()
+	}
 
-			let mut pos = 0;
-			while pos < send_data.len() {
-				let bytes_written = stream.write(&send_data[pos..]).unwrap();
-				pos += bytes_written;
-			}
+	pub fn login(&mut self, username: &str, password: &str) -> Result<String> {
+		let response = self.send_command(&json!({"command": "request_token"}))?;
 
-			let mut buffer = [0; 8];
-			stream.read_exact(&mut buffer).unwrap();
+		let command = auth_command(&response, username, password)?;
+		let response = self.send_command(&command)?;
+		let result = auth_result(&response)?;
 
-			let s = String::from_utf8(buffer.to_vec()).unwrap();
-			let response_len = usize::from_str_radix(&s, 16).unwrap();
+		self.credentials = Some((username.to_string(), password.to_string()));
 
-			let mut buffer = vec![0; response_len];
-			let mut t = 0;
+		Ok(result)
+	}
+}
 
-			while t < response_len {
-				let size = stream.read(&mut buffer[t..]).unwrap();
-				t += size;
-			}
+fn encode_command(command: &Value, enable_msgpack: bool) -> Result<Vec<u8>> {
+	if !enable_msgpack {
+		Ok(serde_json::to_vec(command)?)
+	} else {
+		Ok(rmp_serde::to_vec(command)?)
+	}
+}
 
-			if !self.enable_msgpack {
-				let response = String::from_utf8(buffer).unwrap();
-				match json::parse(&response) {
-					Ok(n) => return Ok(n),
-					Err(err) => return Err(err.to_string()),
-				}
-			} else {
-				let value: rmpv::Value = rmp_serde::from_slice(&buffer[..]).unwrap();
-				let json = serde_json::to_string(&value).unwrap();
-				match json::parse(&json) {
-					Ok(n) => return Ok(n),
-					Err(err) => return Err(err.to_string()),
-				}
+fn decode_response(buffer: Vec<u8>) -> Result<Value> {
+	if !codec::is_msgpack(&buffer) {
+		let response =
+			std::str::from_utf8(&buffer).map_err(|e| BoneError::Decode(e.to_string()))?;
+		Ok(serde_json::from_str(response)?)
+	} else {
+		match rmp_serde::from_slice(&buffer) {
+			Ok(value) => Ok(value),
+			// binary data has no JSON counterpart, it becomes an array of bytes
+			Err(_) => {
+				let value: rmpv::Value = rmp_serde::from_slice(&buffer)?;
+				Ok(serde_json::to_value(value)?)
 			}
-		} else {
-			panic!("Not connected");
 		}
 	}
+}
 
-	pub fn login(&mut self, username: &str, password: &str) -> Result<String, String> {
-		let command = json::object! {
-			"command" => "request_token"
-		};
+fn decode_msgpack_response(buffer: Vec<u8>) -> Result<msgpack::Value> {
+	if !codec::is_msgpack(&buffer) {
+		Ok(msgpack::from_json(&decode_response(buffer)?))
+	} else {
+		msgpack::decode(&buffer)
+	}
+}
 
-		let response = self.send_command(&command)?;
-		let token = &response["payload"]["token"].to_string();
-		let signed_token = Bone::get_signed_token(&password, &token);
-
-		let command = json::object! {
-			"command" => "auth",
-			"payload" => json::object!{
-				"signed_token" => signed_token,
-				"username" => username
-			}
-		};
+/// Error the device answers with when a command needs a login, which is missing
+/// or has timed out. `bone_sim` answers with it as well.
+pub const SESSION_EXPIRED_ERROR: &str = "Not authenticated";
+
+/// Turns the error telling that the login is missing or timed out into
+/// [`BoneError::SessionExpired`]. Other errors, like missing permissions of a
+/// logged in user, are left to the caller.
+fn check_session(error: Option<&str>) -> Result<()> {
+	if let Some(error) = error {
+		if error.trim() == SESSION_EXPIRED_ERROR {
+			return Err(BoneError::SessionExpired(error.to_string()));
+		}
+	}
 
-		let response = self.send_command(&command)?;
+	Ok(())
+}
 
-		let err = &response["payload"]["error"];
+/// Signs the token of a `request_token` response into an `auth` command.
+fn auth_command(response: &Value, username: &str, password: &str) -> Result<Value> {
+	if let Some(err) = response["payload"]["error"].as_str() {
+		return Err(BoneError::Device {
+			message: err.to_string(),
+		});
+	}
 
-		if err.is_string() {
-			return Err(err.to_string());
+	let token = match response["payload"]["token"] {
+		Value::String(ref token) => token.clone(),
+		ref token => token.to_string(),
+	};
+	let signed_token = Bone::get_signed_token(password, &token);
+
+	Ok(json!({
+		"command": "auth",
+		"payload": {
+			"signed_token": signed_token,
+			"username": username
 		}
+	}))
+}
 
-		Ok(response["payload"]["username"].to_string())
+/// User name the `auth` response confirms.
+fn auth_result(response: &Value) -> Result<String> {
+	if let Some(err) = response["payload"]["error"].as_str() {
+		return Err(BoneError::Auth(err.to_string()));
 	}
+
+	Ok(response["payload"]["username"]
+		.as_str()
+		.unwrap_or_default()
+		.to_string())
 }
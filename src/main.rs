@@ -1,9 +1,20 @@
-use bone_api::Bone;
-use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use bone_api::discovery::{self, DiscoveredDevice, DiscoveryOptions};
+use bone_api::known_hosts::KnownHosts;
+use bone_api::msgpack::{self, BinaryFormat};
+use bone_api::proxy::Proxy;
+use bone_api::transport::CommandTransport;
+#[cfg(unix)]
+use bone_api::transport::UnixTransport;
+use bone_api::{
+	Bone, BoneError, ClientIdentity, PeerTrust, ReconnectEvent, ReconnectPolicy, TlsConfig,
+	TlsIdentity,
+};
+use clap::Parser;
+use serde_json::{json, Value};
 use std::io::*;
+use std::net::{IpAddr, Ipv6Addr};
 use std::path::PathBuf;
-use std::time::Instant;
-use clap::Parser;
+use std::time::{Duration, Instant};
 
 use crossterm::{
 	execute,
@@ -11,14 +22,13 @@ use crossterm::{
 	terminal::size,
 };
 
-use current_platform::CURRENT_PLATFORM;
 use rustyline::{error::ReadlineError, CompletionType, Config, Editor};
 use textplots::{Chart, Plot, Shape};
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[derive(Parser, Debug, Clone)]
+#[command(version, about, long_about = None, disable_version_flag = true)]
 pub struct Opt {
 	#[arg(short, long, default_value = "localhost")]
 	connect: String,
@@ -26,19 +36,49 @@ pub struct Opt {
 	#[arg(short, long)]
 	port: Option<String>,
 
+	#[arg(long, default_value = "tcp")]
+	transport: String,
+
+	#[arg(long)]
+	proxy: Option<String>,
+
 	#[arg(long)]
 	unencrypted: bool,
 
+	#[arg(long)]
+	auto: bool,
+
+	#[arg(long)]
+	insecure: bool,
+
+	#[arg(long)]
+	ca_file: Option<PathBuf>,
+
+	#[arg(long)]
+	ca_path: Option<PathBuf>,
+
+	#[arg(long)]
+	cert: Option<PathBuf>,
+
+	#[arg(long)]
+	key: Option<PathBuf>,
+
 	#[arg(short, long)]
 	msgpack: bool,
 
 	#[arg(short, long)]
 	no_pretty: bool,
 
+	#[arg(long, default_value = "base64")]
+	binary: BinaryFormat,
+
 	#[arg(short, long)]
 	response_time: bool,
 
 	#[arg(long)]
+	batch: bool,
+
+	#[arg(short = 'V', long)]
 	version: bool,
 
 	#[arg(long)]
@@ -53,54 +93,215 @@ pub struct Opt {
 	#[arg(long)]
 	serial: Option<u32>,
 
+	#[arg(long)]
+	interface: Option<String>,
+
+	#[arg(long)]
+	probe_serials: Option<String>,
+
+	#[arg(long)]
+	serial_of: Option<String>,
+
+	#[arg(long)]
+	no_reconnect: bool,
+
+	#[arg(long, default_value = "60")]
+	keepalive: f64,
+
+	#[arg(long, default_value = "10")]
+	connect_timeout: f64,
+
+	#[arg(long, default_value = "30")]
+	read_timeout: f64,
+
+	#[arg(long, default_value = "30")]
+	write_timeout: f64,
+
 	command: Option<String>,
 }
 
 fn main() -> std::io::Result<()> {
-	let opt = Opt::parse();
+	let mut opt = Opt::parse();
 
 	if opt.version {
 		println!("bone_shell version: {}", VERSION);
 		return Ok(());
 	}
 
-	let ip;
+	if let Some(ref address) = opt.serial_of {
+		match discovery::serial_from_host(address) {
+			Some(serial) => println!("{}", serial),
+			None => {
+				write_stderr(&format!("{} is not a BeMoS device address", address)).unwrap();
+				std::process::exit(1)
+			}
+		}
 
-	if let Some(serial) = opt.serial {
-		ip = get_ipv6_link_local_from_serial(serial);
-	} else {
-		ip = opt.connect;
+		return Ok(());
 	}
 
-	let unencrypted = if ip == "localhost" {
-		true
-	} else {
-		opt.unencrypted
-	};
+	let mut serial = opt.serial;
+	let mut ip;
+	// interfaces the address derived from --serial is tried on
+	let mut interfaces: Vec<String> = Vec::new();
+
+	if opt.command.as_deref() == Some("discover") {
+		let device = match discover_devices(&opt) {
+			Some(device) => device,
+			None => return Ok(()),
+		};
 
-	let port = if let Some(port) = opt.port {
-		port
+		opt.command = None;
+		serial = device.serial_number.parse().ok();
+		ip = device.host();
+	} else if let Some(serial) = serial {
+		ip = discovery::link_local_address(serial).to_string();
+
+		match opt.interface {
+			Some(ref interface) => ip = format!("{}%{}", ip, interface),
+			None => interfaces = discovery::link_local_interfaces().unwrap_or_default(),
+		}
 	} else {
-		if unencrypted {
-			"6450".into()
-		} else {
-			"6451".into()
+		ip = opt
+			.connect
+			.trim_start_matches('[')
+			.trim_end_matches(']')
+			.to_string();
+
+		// the zone id only applies to link-local addresses
+		if let Some(ref interface) = opt.interface {
+			if !is_link_local(&ip) {
+				eprintln!("--interface needs a link-local IPv6 address or --serial");
+				std::process::exit(1)
+			}
+
+			if !ip.contains('%') {
+				ip = format!("{}%{}", ip, interface);
+			}
 		}
+	}
+
+	let loopback = is_loopback(&ip);
+
+	if opt.batch && opt.command.is_some() {
+		eprintln!("--batch reads the commands from stdin and can't be combined with a command");
+		std::process::exit(1)
+	}
+
+	if opt.auto && (opt.port.is_some() || opt.unencrypted) {
+		eprintln!("--auto can't be combined with --port or --unencrypted");
+		std::process::exit(1)
+	}
+
+	let (auto, mut unencrypted) = encryption(&opt, loopback);
+
+	let mut port = match opt.port {
+		Some(ref port) => port.clone(),
+		None if unencrypted => "6450".into(),
+		None => "6451".into(),
 	};
 
-	writeln_dimmed(&format!("Trying to connect to [{}]:{}...", ip, port)).unwrap();
+	if opt.transport != "tcp" && opt.proxy.is_some() {
+		eprintln!("--proxy can only be used with the tcp transport");
+		std::process::exit(1)
+	}
+
+	// without --interface the first interface the device answers on is used
+	let try_interfaces = interfaces.len() > 1 && opt.transport == "tcp" && opt.proxy.is_none();
+
+	if !try_interfaces {
+		if let Some(interface) = interfaces.first() {
+			ip = format!("{}%{}", ip, interface);
+		}
+
+		interfaces.clear();
+	}
 
 	let logged_in;
 	let username;
 
-	let mut bone1 = Bone::new(&ip, &port, opt.msgpack, !unencrypted);
-	match bone1.connect() {
+	// resolved once, so the password of a PKCS#12 archive is asked for only once
+	// even if several interfaces or the unencrypted fallback are tried, and not
+	// at all for unencrypted connections
+	let client_identity = if unencrypted {
+		None
+	} else {
+		get_client_identity(&opt)
+	};
+
+	let mut result = open_connection(
+		&opt,
+		&ip,
+		&port,
+		serial,
+		&interfaces,
+		unencrypted,
+		&client_identity,
+	);
+
+	// certificate errors don't fall back, they must not silently downgrade the connection
+	if let Err(ref e) = result {
+		if auto && e.is_tls_unavailable() {
+			write_stderr(&format!(
+				"Warning: no encrypted connection to [{}]:{} ({}), falling back to unencrypted port 6450",
+				ip, port, e
+			))
+			.unwrap();
+
+			unencrypted = true;
+			port = "6450".into();
+			result = open_connection(
+				&opt,
+				&ip,
+				&port,
+				serial,
+				&interfaces,
+				unencrypted,
+				&client_identity,
+			);
+		}
+	}
+
+	let result = result.map(|(bone, host)| {
+		ip = host;
+		bone
+	});
+
+	let mut bone1 = match result {
+		Err(BoneError::FingerprintMismatch {
+			name,
+			known,
+			presented,
+		}) => {
+			write_stderr(&format!(
+				"@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@\n\
+				 @    WARNING: DEVICE CERTIFICATE HAS CHANGED!             @\n\
+				 @@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@\n\
+				 Someone could be intercepting the connection to {name}.\n\
+				 Known fingerprint:     {known}\n\
+				 Presented fingerprint: {presented}\n\
+				 If the change is expected, connect with --insecure and run `known_hosts accept`."
+			))
+			.unwrap();
+			std::process::exit(1)
+		}
 		Err(e) => {
-			eprintln!("Error connecting to [{ip}]:{port}: {e}");
+			eprintln!("Error connecting to [{ip}]:{port}{}: {e}", serial_note(&ip));
 			std::process::exit(1)
 		}
-		_ => (),
-	}
+		Ok(bone) => {
+			if bone.peer_trust() == Some(PeerTrust::FirstUse) {
+				writeln_dimmed(&format!(
+					"Added certificate {} of {} to known hosts",
+					bone.peer_fingerprint().unwrap_or_default(),
+					bone.known_hosts_name()
+				))
+				.unwrap();
+			}
+
+			bone
+		}
+	};
 
 	if let Some(username_tmp) = &opt.username {
 		if let Some(password) = &opt.password {
@@ -124,29 +325,47 @@ fn main() -> std::io::Result<()> {
 		username = String::from("");
 	}
 
-	if let Some(command) = &opt.command {
+	if opt.batch {
+		// batch mode
+		if !batch_operations(
+			&mut bone1,
+			!opt.no_pretty,
+			opt.binary,
+			std::io::stdout().is_terminal() && opt.response_time,
+		) {
+			std::process::exit(1)
+		}
+	} else if let Some(command) = &opt.command {
 		// command mode
-		let command = json::parse(&command).unwrap();
-		command_operations(
+		let command = serde_json::from_str::<Value>(command).unwrap();
+		if let Err(e) = command_operations(
 			&mut bone1,
 			&command,
 			!opt.no_pretty,
+			opt.binary,
 			std::io::stdout().is_terminal() && opt.response_time,
 			false,
-		);
+		) {
+			write_stderr(&format!("Error: {}", e)).unwrap();
+			std::process::exit(1)
+		}
 	} else if !std::io::stdin().is_terminal() {
 		// pipe mode
 		let mut command = String::new();
 		stdin().read_line(&mut command).unwrap();
 
-		let command = json::parse(&command).unwrap();
-		command_operations(
+		let command = serde_json::from_str::<Value>(&command).unwrap();
+		if let Err(e) = command_operations(
 			&mut bone1,
 			&command,
 			!opt.no_pretty,
+			opt.binary,
 			std::io::stdout().is_terminal() && opt.response_time,
 			false,
-		);
+		) {
+			write_stderr(&format!("Error: {}", e)).unwrap();
+			std::process::exit(1)
+		}
 	} else {
 		// shell mode
 
@@ -164,36 +383,40 @@ fn main() -> std::io::Result<()> {
 		let mut rl = Editor::<(), _>::with_config(config).unwrap();
 
 		if let Some(path) = history_path.to_str() {
-			match rl.load_history(path) {
-				Ok(_) => (),
-				Err(_) => (),
-			}
+			let _ = rl.load_history(path);
 		}
 
-		let data = match bone1.send_command(&json::object! {"command" => "serial_number"}) {
+		let data = match bone1.send_command(&json!({"command": "serial_number"})) {
 			Ok(n) => n,
-			Err(_err) => json::object! {"error" => "missing"},
+			Err(_err) => json!({"error": "missing"}),
 		};
 
 		let alias = &data["payload"]["alias"];
 		let serial_number = &data["payload"]["serial_number"];
 		let cnt_str;
 
-		if !alias.is_string() {
-			if !serial_number.is_string() {
-				cnt_str = String::from("");
-			} else {
-				cnt_str = serial_number.to_string();
-			}
-		} else {
+		if let Some(alias) = alias.as_str() {
 			cnt_str = alias.to_string();
+		} else if let Some(serial_number) = serial_number.as_str() {
+			cnt_str = serial_number.to_string();
+		} else {
+			cnt_str = match discovery::serial_from_host(&ip) {
+				Some(serial) => format!("{} (serial {})", ip, serial),
+				None => String::from(""),
+			};
 		}
 
 		writeln_dimmed(&format!(
-			"Connected to [{}]:{} ({})",
+			"Connected to [{}]:{}{} ({}), {}",
 			ip,
 			port,
-			serial_number.to_string()
+			serial_note(&ip),
+			serial_number,
+			if unencrypted {
+				"unencrypted"
+			} else {
+				"encrypted"
+			}
 		))
 		.unwrap();
 
@@ -223,6 +446,15 @@ fn main() -> std::io::Result<()> {
 
 			let _ = rl.add_history_entry(command.clone());
 
+			// `:json <command>` and `:msgpack <command>` override the encoding of one command
+			let mut msgpack = None;
+			for (prefix, enable) in [(":json ", false), (":msgpack ", true)] {
+				if let Some(rest) = command.strip_prefix(prefix) {
+					command = rest.trim_start().to_string();
+					msgpack = Some(enable);
+				}
+			}
+
 			if let Some(first_char) = command.chars().next() {
 				if first_char != '{' && first_char != '[' {
 					let tmp_len = command.trim_end().len();
@@ -233,25 +465,18 @@ fn main() -> std::io::Result<()> {
 					}
 
 					if command == "login" {
-						let mut username = String::new();
-						print!("username: ");
-						stdout().flush().unwrap();
-						stdin().read_line(&mut username).unwrap();
-						let tmp_len = username.trim_end().len();
-						username.truncate(tmp_len);
-
-						let password = rpassword::prompt_password("password: ").unwrap();
-
-						let result = bone1.login(&username, &password);
-						match result {
-							Err(msg) => {
-								write_stderr(&format!("Error while logging in: {}", msg)).unwrap()
-							}
-							_ => writeln_dimmed(&format!(
-								"Successfully authenticated as user {}",
-								username
-							))
-							.unwrap(),
+						prompt_login(&mut bone1);
+						continue;
+					}
+
+					if command == ":set" || command.starts_with(":set ") {
+						set_operations(&mut bone1, &command[4..]);
+						continue;
+					}
+
+					if command == "known_hosts" || command.starts_with("known_hosts ") {
+						if let Err(e) = known_hosts_operations(&bone1, &command[11..]) {
+							write_stderr(&format!("Error: {}", e)).unwrap();
 						}
 
 						continue;
@@ -268,7 +493,7 @@ fn main() -> std::io::Result<()> {
 								if first_char != '{' && first_char != '[' {
 									command = parse_parameters(command_name, payload, opt.api);
 								} else {
-									let payload = match json::parse(s.1) {
+									let payload = match serde_json::from_str::<Value>(s.1) {
 										Ok(n) => n,
 										Err(err) => {
 											write_stderr(&format!(
@@ -279,14 +504,13 @@ fn main() -> std::io::Result<()> {
 											continue;
 										}
 									};
-									command = json::object!{"command": command_name, "payload": payload, "api": opt.api}.dump();
+									command = json!({"command": command_name, "payload": payload, "api": opt.api}).to_string();
 								}
 							}
 						}
 						None => {
 							let command_name = parse_shortcuts(&command);
-							command =
-								json::object! {"command": command_name, "api": opt.api}.dump();
+							command = json!({"command": command_name, "api": opt.api}).to_string();
 						}
 					}
 				}
@@ -294,17 +518,53 @@ fn main() -> std::io::Result<()> {
 				continue;
 			}
 
-			let result = json::parse(&command);
+			let result = serde_json::from_str::<Value>(&command);
 			match result {
 				Err(msg) => write_stderr(&format!("invalid input: {}", msg)).unwrap(),
 				Ok(command) => {
-					command_operations(
+					let default_msgpack = bone1.msgpack();
+					if let Some(msgpack) = msgpack {
+						bone1.set_msgpack(msgpack);
+					}
+
+					let mut result = command_operations(
 						&mut bone1,
 						&command,
 						!opt.no_pretty,
+						opt.binary,
 						opt.response_time,
 						true,
 					);
+
+					if let Err(BoneError::SessionExpired(ref message)) = result {
+						writeln_dimmed(&format!(
+							"Session expired ({}), please log in again",
+							message
+						))
+						.unwrap();
+
+						if prompt_login(&mut bone1) {
+							result = command_operations(
+								&mut bone1,
+								&command,
+								!opt.no_pretty,
+								opt.binary,
+								opt.response_time,
+								false,
+							);
+						}
+					}
+
+					bone1.set_msgpack(default_msgpack);
+
+					if let Err(e) = result {
+						write_stderr(&format!("Error: {}", e)).unwrap();
+
+						if !bone1.is_connected() {
+							write_stderr("Connection closed").unwrap();
+							break;
+						}
+					}
 				}
 			}
 		}
@@ -320,35 +580,491 @@ fn main() -> std::io::Result<()> {
 	Ok(())
 }
 
-fn get_ipv6_link_local_from_serial(serial: u32) -> String {
-	let network_interfaces = NetworkInterface::show().unwrap();
+/// Asks for user name and password and logs in, returns whether it succeeded.
+fn prompt_login(bone: &mut Bone) -> bool {
+	let mut username = String::new();
+	print!("username: ");
+	stdout().flush().unwrap();
+	stdin().read_line(&mut username).unwrap();
+	let tmp_len = username.trim_end().len();
+	username.truncate(tmp_len);
+
+	let password = rpassword::prompt_password("password: ").unwrap();
+
+	let result = bone.login(&username, &password);
+	match result {
+		Err(msg) => {
+			write_stderr(&format!("Error while logging in: {}", msg)).unwrap();
+			false
+		}
+		_ => {
+			writeln_dimmed(&format!("Successfully authenticated as user {}", username)).unwrap();
+			true
+		}
+	}
+}
 
-	let mut interface = None;
+fn set_operations(bone: &mut Bone, args: &str) {
+	let mut args = args.split_whitespace();
+
+	match (args.next(), args.next()) {
+		(Some("msgpack"), value) => {
+			match value {
+				Some("on") => bone.set_msgpack(true),
+				Some("off") => bone.set_msgpack(false),
+				None => {}
+				Some(_) => {
+					write_stderr("usage: :set msgpack [on | off]").unwrap();
+					return;
+				}
+			}
 
-	if !CURRENT_PLATFORM.to_string().contains("windows") {
-		for itf in network_interfaces.iter() {
-			let addrs = &itf.addr;
-			for addr in addrs.iter() {
-				if addr.ip().is_ipv6() && !addr.ip().is_loopback() {
-					if addr.ip().to_string().starts_with("fe80::") {
-						interface = Some(itf.name.clone());
-						break;
-					}
+			let state = if bone.msgpack() { "on" } else { "off" };
+			writeln_dimmed(&format!("msgpack is {}", state)).unwrap();
+		}
+		_ => write_stderr("usage: :set msgpack [on | off]").unwrap(),
+	}
+}
+
+fn known_hosts_operations(bone: &Bone, args: &str) -> std::result::Result<(), BoneError> {
+	let path = match KnownHosts::default_path() {
+		Some(path) => path,
+		None => {
+			write_stderr("no home directory to store known hosts in").unwrap();
+			return Ok(());
+		}
+	};
+
+	let mut known_hosts = KnownHosts::load(&path)?;
+	let mut args = args.split_whitespace();
+
+	match (args.next(), args.next()) {
+		(None, _) | (Some("list"), _) => {
+			for (name, fingerprint) in known_hosts.entries() {
+				println!("{} {}", name, fingerprint);
+			}
+		}
+		(Some("accept"), name) => {
+			let fingerprint = match bone.peer_fingerprint() {
+				Some(fingerprint) => fingerprint,
+				None => {
+					write_stderr("current connection is not encrypted").unwrap();
+					return Ok(());
 				}
+			};
+
+			let name = name.map_or_else(|| bone.known_hosts_name(), String::from);
+			known_hosts.insert(&name, fingerprint);
+			known_hosts.save()?;
+
+			writeln_dimmed(&format!("Added certificate {} of {}", fingerprint, name)).unwrap();
+		}
+		(Some("forget"), Some(name)) => {
+			if known_hosts.remove(name) {
+				known_hosts.save()?;
+				writeln_dimmed(&format!("Removed {} from known hosts", name)).unwrap();
+			} else {
+				write_stderr(&format!("{} is not a known host", name)).unwrap();
+			}
+		}
+		_ => write_stderr("usage: known_hosts [list | accept [name] | forget <name>]").unwrap(),
+	}
+
+	Ok(())
+}
+
+fn get_client_identity(opt: &Opt) -> Option<ClientIdentity> {
+	let cert = opt.cert.clone()?;
+
+	if let Some(key) = &opt.key {
+		return Some(ClientIdentity::Pem {
+			cert,
+			key: key.clone(),
+		});
+	}
+
+	let extension = cert.extension().and_then(|e| e.to_str()).unwrap_or("");
+
+	if extension.eq_ignore_ascii_case("p12") || extension.eq_ignore_ascii_case("pfx") {
+		let password = if std::io::stdin().is_terminal() {
+			rpassword::prompt_password("certificate password: ").unwrap()
+		} else {
+			String::new()
+		};
+
+		Some(ClientIdentity::Pkcs12 {
+			path: cert,
+			password,
+		})
+	} else {
+		// certificate and key in one PEM file
+		Some(ClientIdentity::Pem {
+			key: cert.clone(),
+			cert,
+		})
+	}
+}
+
+/// Certificate checks of the command line for the device with `serial`.
+fn tls_config(
+	opt: &Opt,
+	serial: Option<u32>,
+	client_identity: Option<ClientIdentity>,
+) -> TlsConfig {
+	TlsConfig {
+		verify: !opt.insecure,
+		ca_file: opt.ca_file.clone(),
+		ca_path: opt.ca_path.clone(),
+		identity: match serial {
+			Some(serial) => TlsIdentity::Serial(serial),
+			None => TlsIdentity::Host,
+		},
+		known_hosts: if opt.insecure {
+			None
+		} else {
+			KnownHosts::default_path()
+		},
+		pin_first_use: true,
+		client_identity,
+	}
+}
+
+/// Client for `ip` with the connection settings of the command line.
+fn configure_bone(
+	opt: &Opt,
+	ip: &str,
+	port: &str,
+	unencrypted: bool,
+	proxy: Option<Proxy>,
+	tls_config: TlsConfig,
+) -> Bone {
+	let mut bone = Bone::new(ip, port, opt.msgpack, !unencrypted);
+
+	if let Some(proxy) = proxy {
+		bone.set_proxy(proxy);
+	}
+
+	bone.set_connect_timeout(to_timeout(opt.connect_timeout));
+	bone.set_tls_config(tls_config);
+	bone.set_read_timeout(to_timeout(opt.read_timeout)).unwrap();
+	bone.set_write_timeout(to_timeout(opt.write_timeout))
+		.unwrap();
+	bone.set_keepalive(to_timeout(opt.keepalive)).unwrap();
+
+	if !opt.no_reconnect {
+		bone.set_reconnect_policy(Some(ReconnectPolicy::default()));
+		bone.set_reconnect_handler(print_reconnect_event);
+	}
+
+	bone
+}
+
+fn print_reconnect_event(event: &ReconnectEvent) {
+	let notice = match event {
+		ReconnectEvent::Lost(e) => format!("Connection lost ({}), reconnecting...", e),
+		ReconnectEvent::Retrying {
+			attempt,
+			delay,
+			error,
+		} => format!(
+			"Reconnect attempt {} failed ({}), retrying in {:.1} s",
+			attempt,
+			error,
+			delay.as_secs_f64()
+		),
+		ReconnectEvent::Reconnected { .. } => String::from("Reconnected"),
+		ReconnectEvent::Reauthenticated { username } => {
+			format!("Authenticated again as user {}", username)
+		}
+		ReconnectEvent::GaveUp(e) => format!("Reconnecting failed: {}", e),
+	};
+
+	writeln_dimmed(&notice).unwrap();
+}
+
+/// Whether to fall back to the unencrypted port and whether to start with it.
+/// Without port the encrypted one is tried first, loopback connections are
+/// unencrypted. Given TLS options never fall back to an unencrypted connection.
+fn encryption(opt: &Opt, loopback: bool) -> (bool, bool) {
+	let tls_options =
+		opt.ca_file.is_some() || opt.ca_path.is_some() || opt.cert.is_some() || opt.insecure;
+	let plain_loopback = loopback && opt.port.is_none() && !tls_options;
+	let auto =
+		opt.auto || (opt.port.is_none() && !opt.unencrypted && !plain_loopback && !tls_options);
+	let unencrypted = !auto && (opt.unencrypted || plain_loopback);
+
+	(auto, unencrypted)
+}
+
+/// Connects to `ip` on `port`, or to the first of `interfaces` the device with
+/// `serial` answers on if given. Returns the client and the host it connected to.
+fn open_connection(
+	opt: &Opt,
+	ip: &str,
+	port: &str,
+	serial: Option<u32>,
+	interfaces: &[String],
+	unencrypted: bool,
+	client_identity: &Option<ClientIdentity>,
+) -> std::result::Result<(Bone, String), BoneError> {
+	if !interfaces.is_empty() {
+		writeln_dimmed(&format!(
+			"Trying to connect to [{}]:{}{} on {}...",
+			ip,
+			port,
+			serial_note(ip),
+			interfaces.join(", ")
+		))
+		.unwrap();
+
+		let serial = serial.unwrap_or_default();
+		let (opt, port) = (opt.clone(), port.to_string());
+
+		// only the connection that is kept adds the device to the known hosts
+		let tls_config = TlsConfig {
+			pin_first_use: false,
+			..tls_config(&opt, Some(serial), client_identity.clone())
+		};
+
+		let (bone, interface) = discovery::connect_serial(serial, interfaces, move |host| {
+			let mut bone = configure_bone(&opt, host, &port, unencrypted, None, tls_config.clone());
+			bone.connect()?;
+			check_serial(&mut bone, serial)?;
+			Ok(bone)
+		})?;
+
+		bone.pin_peer()?;
+
+		return Ok((bone, format!("{}%{}", ip, interface)));
+	}
+
+	let proxy = if opt.transport == "tcp" {
+		let proxy = match opt.proxy {
+			Some(ref url) => Proxy::parse(url).map(Some),
+			None => Proxy::from_env(ip, !unencrypted),
+		};
+
+		match proxy {
+			Ok(proxy) => proxy,
+			Err(e) => {
+				eprintln!("{}", e);
+				std::process::exit(1)
 			}
 		}
+	} else {
+		None
+	};
+
+	if let Some(ref proxy) = proxy {
+		writeln_dimmed(&format!("Using proxy {}:{}", proxy.host, proxy.port)).unwrap();
+	}
+
+	if opt.transport == "tcp" {
+		writeln_dimmed(&format!(
+			"Trying to connect to [{}]:{}{}...",
+			ip,
+			port,
+			serial_note(ip)
+		))
+		.unwrap();
+	} else {
+		writeln_dimmed(&format!(
+			"Trying to connect to [{}]:{}{} via {}...",
+			ip,
+			port,
+			serial_note(ip),
+			opt.transport
+		))
+		.unwrap();
+	}
+
+	let tls_config = tls_config(opt, serial, client_identity.clone());
+	let mut bone = configure_bone(opt, ip, port, unencrypted, proxy, tls_config);
+	if let Err(e) = set_transport(&mut bone, &opt.transport, ip, port) {
+		eprintln!("{}", e);
+		std::process::exit(1)
+	}
+
+	bone.connect()?;
+
+	Ok((bone, ip.to_string()))
+}
+
+/// Whether `host` is `localhost` or a loopback address.
+fn is_loopback(host: &str) -> bool {
+	let address = host.trim_start_matches('[').trim_end_matches(']');
+
+	match address.split('%').next().map(str::parse::<IpAddr>) {
+		Some(Ok(address)) => address.is_loopback(),
+		_ => host.eq_ignore_ascii_case("localhost"),
+	}
+}
+
+/// Whether `host` is a link-local IPv6 address, with or without zone id.
+fn is_link_local(host: &str) -> bool {
+	let address = host.trim_start_matches('[').trim_end_matches(']');
+
+	match address.split('%').next().map(str::parse::<Ipv6Addr>) {
+		Some(Ok(address)) => address.is_unicast_link_local(),
+		_ => false,
+	}
+}
+
+/// Makes sure the device that answered is the one with `serial`.
+fn check_serial(bone: &mut Bone, serial: u32) -> std::result::Result<(), BoneError> {
+	let found = bone.serial_number()?.serial_number;
+
+	if found == serial.to_string() {
+		Ok(())
+	} else {
+		Err(BoneError::Device {
+			message: format!("found serial number {} instead of {}", found, serial),
+		})
+	}
+}
+
+/// Applies `--transport`: `tcp`, `unix:<path>` or `exec:<command>`. `%h` and `%p`
+/// in the command are replaced by host and port, like in the ProxyCommand of ssh.
+fn set_transport(
+	bone: &mut Bone,
+	transport: &str,
+	ip: &str,
+	port: &str,
+) -> std::result::Result<(), String> {
+	match transport.split_once(':') {
+		None if transport == "tcp" => {}
+		#[cfg(unix)]
+		Some(("unix", path)) => bone.set_transport(UnixTransport::new(path)),
+		Some(("exec", command)) => bone.set_transport(CommandTransport::new(
+			&command.replace("%h", ip).replace("%p", port),
+		)),
+		_ => return Err(format!("Unsupported transport {}", transport)),
 	}
 
-	let hex = format!("{:04x}", serial);
-	if interface.is_some() {
-		format!(
-			"fe80::b5:b1ff:fe{}:{}%{}",
-			&hex[..2],
-			&hex[2..],
-			interface.unwrap()
-		)
+	Ok(())
+}
+
+/// `, serial N` if `ip` is the link-local address of a device, a warning if it is
+/// another link-local address.
+fn serial_note(ip: &str) -> String {
+	if let Some(serial) = discovery::serial_from_host(ip) {
+		return format!(", serial {}", serial);
+	}
+
+	if is_link_local(ip) {
+		String::from(", not a BeMoS device address")
 	} else {
-		format!("fe80::b5:b1ff:fe{}:{}", &hex[..2], &hex[2..])
+		String::new()
+	}
+}
+
+fn to_timeout(seconds: f64) -> Option<Duration> {
+	if seconds > 0. {
+		Some(Duration::from_secs_f64(seconds))
+	} else {
+		None
+	}
+}
+
+/// Lists the devices found on the link-local network and lets the user pick one
+/// to connect to, if running in a terminal.
+fn discover_devices(opt: &Opt) -> Option<DiscoveredDevice> {
+	let mut options = DiscoveryOptions {
+		encrypted: !opt.unencrypted,
+		..Default::default()
+	};
+
+	options.port = match opt.port {
+		Some(ref port) => port.clone(),
+		None if opt.unencrypted => "6450".into(),
+		None => "6451".into(),
+	};
+
+	if let Some(ref range) = opt.probe_serials {
+		let bounds = range
+			.split_once('-')
+			.and_then(|(from, to)| Some((from.trim().parse().ok()?, to.trim().parse().ok()?)));
+
+		match bounds {
+			Some((from, to)) => options.serials = Some(from..=to),
+			None => {
+				eprintln!("--probe-serials expects a range like 1-1000");
+				std::process::exit(1)
+			}
+		}
+	}
+
+	writeln_dimmed("Discovering devices...").unwrap();
+
+	let devices = match discovery::discover(&options) {
+		Ok(devices) => devices,
+		Err(e) => {
+			eprintln!("Error discovering devices: {}", e);
+			std::process::exit(1)
+		}
+	};
+
+	if devices.is_empty() {
+		writeln_dimmed("No devices found").unwrap();
+		return None;
+	}
+
+	let rows: Vec<[String; 5]> = devices
+		.iter()
+		.enumerate()
+		.map(|(i, device)| {
+			[
+				(i + 1).to_string(),
+				device.serial_number.clone(),
+				device.alias.clone().unwrap_or_default(),
+				device.address.to_string(),
+				device.interface.clone(),
+			]
+		})
+		.collect();
+
+	let header = [
+		"#".to_string(),
+		"Serial".to_string(),
+		"Alias".to_string(),
+		"Address".to_string(),
+		"Interface".to_string(),
+	];
+
+	let mut widths = [0; 5];
+	for row in rows.iter().chain(std::iter::once(&header)) {
+		for (width, cell) in widths.iter_mut().zip(row) {
+			*width = (*width).max(cell.chars().count());
+		}
+	}
+
+	for row in std::iter::once(&header).chain(rows.iter()) {
+		let line: Vec<String> = row
+			.iter()
+			.zip(widths)
+			.map(|(cell, width)| format!("{:width$}", cell, width = width))
+			.collect();
+		println!("{}", line.join("  ").trim_end());
+	}
+
+	if !stdin().is_terminal() {
+		return None;
+	}
+
+	loop {
+		print!("Connect to [1-{}, empty to quit]: ", devices.len());
+		stdout().flush().unwrap();
+
+		let mut choice = String::new();
+		stdin().read_line(&mut choice).unwrap();
+
+		match choice.trim() {
+			"" => return None,
+			choice => match choice.parse::<usize>() {
+				Ok(i) if (1..=devices.len()).contains(&i) => return Some(devices[i - 1].clone()),
+				_ => write_stderr("Invalid choice").unwrap(),
+			},
+		}
 	}
 }
 
@@ -365,17 +1081,106 @@ fn parse_shortcuts(command: &str) -> &str {
 fn parse_parameters(command: &str, argument: &str, api: u32) -> String {
 	match command {
 		"channel_data" | "channel_attributes" => match argument {
-			"--all" => json::object! {"command": command, "payload": {"all": true}, "api": api}.dump(),
-			"--hidden" => json::object!{"command": command, "payload": {"all": true, "hidden": true}, "api": api}.dump(),
-			"--list" => json::object!{"command": command, "payload": {"all": true, "filter": [""], "hidden": true}, "api": api}.dump(),
-			&_ => json::object! {"command": command, "payload": {"name": argument}, "api": api}.dump(),
+			"--all" => json!({"command": command, "payload": {"all": true}, "api": api}).to_string(),
+			"--hidden" => json!({"command": command, "payload": {"all": true, "hidden": true}, "api": api}).to_string(),
+			"--list" => json!({"command": command, "payload": {"all": true, "filter": [""], "hidden": true}, "api": api}).to_string(),
+			&_ => json!({"command": command, "payload": {"name": argument}, "api": api}).to_string(),
 		},
 		"sync" | "sync_json" => match argument {
-			&_ => json::object! {"command": command, "payload": {"filter": [argument]}, "api": api}.dump(),
+			&_ => json!({"command": command, "payload": {"filter": [argument]}, "api": api}).to_string(),
 		},
-		"remove_user" => json::object! {"command": command, "payload": {"username": argument}, "api": api}.dump(),
-		&_ => json::object! {"command": command, "api": api}.dump(),
+		"remove_user" => json!({"command": command, "payload": {"username": argument}, "api": api}).to_string(),
+		&_ => json!({"command": command, "api": api}).to_string(),
+	}
+}
+
+/// Sends the commands read from stdin, one per line, pipelined and prints the
+/// responses in order. Raw data commands are rejected. Returns whether all
+/// commands succeeded.
+fn batch_operations(
+	bone: &mut Bone,
+	pretty: bool,
+	binary: BinaryFormat,
+	response_time: bool,
+) -> bool {
+	let mut commands = Vec::new();
+
+	for (i, line) in stdin().lines().enumerate() {
+		let line = match line {
+			Ok(line) => line,
+			Err(e) => {
+				write_stderr(&format!("Error: {}", e)).unwrap();
+				return false;
+			}
+		};
+
+		if line.trim().is_empty() {
+			continue;
+		}
+
+		match serde_json::from_str::<Value>(&line) {
+			Ok(command) => commands.push(command),
+			Err(e) => {
+				write_stderr(&format!("invalid input in line {}: {}", i + 1, e)).unwrap();
+				return false;
+			}
+		}
+	}
+
+	// with the response time the first command is sent alone, its round trip
+	// estimates how long sending the commands one by one would take
+	let start = Instant::now();
+	let mut results = Vec::new();
+	let mut round_trip = None;
+
+	if response_time && commands.len() > 1 {
+		results.push(bone.send_command_msgpack(&commands[0]));
+		round_trip = Some(start.elapsed());
+		results.extend(bone.send_batch_msgpack(&commands[1..]));
+	} else {
+		results = bone.send_batch_msgpack(&commands);
+	}
+
+	let duration = start.elapsed();
+
+	let mut success = true;
+
+	for result in results {
+		match result {
+			Ok(response) => println!("{}", msgpack::render(&response, pretty, binary)),
+			Err(e) => {
+				write_stderr(&format!("Error: {}", e)).unwrap();
+				success = false;
+			}
+		}
 	}
+
+	if response_time && !commands.is_empty() {
+		let seconds = duration.as_secs_f64();
+
+		writeln_dimmed(&format!(
+			"took {} ms for {} commands, {:.2} ms per command, {:.0} commands/s",
+			duration.as_millis(),
+			commands.len(),
+			seconds * 1E3 / commands.len() as f64,
+			commands.len() as f64 / seconds
+		))
+		.unwrap();
+
+		if let Some(round_trip) = round_trip {
+			let sequential = round_trip.as_secs_f64() * commands.len() as f64;
+
+			writeln_dimmed(&format!(
+				"one by one about {:.0} ms at the {:.2} ms round trip of the first command, {:.1}x faster",
+				sequential * 1E3,
+				round_trip.as_secs_f64() * 1E3,
+				sequential / seconds
+			))
+			.unwrap();
+		}
+	}
+
+	success
 }
 
 fn get_term_size() -> (u32, u32) {
@@ -386,106 +1191,74 @@ fn get_term_size() -> (u32, u32) {
 }
 
 fn create_xy<T: Clone>(data: &[T], dt: f32) -> Vec<(f32, T)> {
-	let mut out = Vec::new();
-
-	for t in 0..data.len() {
-		out.push((t as f32 * dt, data[t].clone()));
-	}
-
-	out
+	data.iter()
+		.enumerate()
+		.map(|(t, v)| (t as f32 * dt, v.clone()))
+		.collect()
 }
 
 fn command_operations(
 	bone: &mut Bone,
-	command: &json::JsonValue,
+	command: &Value,
 	pretty: bool,
+	binary: BinaryFormat,
 	response_time: bool,
 	echo_command: bool,
-) {
+) -> std::result::Result<(), BoneError> {
 	if echo_command {
-		writeln_dimmed(&command.dump()).unwrap();
+		writeln_dimmed(&command.to_string()).unwrap();
 	}
 
 	let start = Instant::now();
 	let duration;
 	if command["command"] == "sync" {
-		let data = bone.send_sync_command(&command).unwrap();
+		let data = bone.send_sync_command(command)?;
 		duration = start.elapsed().as_millis();
 
-		let cycle_time = {
-			let parsed = bone.send_command(&json::object! {"command" => "cycle_time"});
-
-			match parsed {
-				Ok(n) => match n["payload"]["cycle_time"].as_number() {
-					Some(n) => f32::from(n) * 1E-6,
-					_ => 2E-4,
-				},
-				Err(_err) => 2E-4,
-			}
-		};
+		let cycle_time = bone
+			.cycle_time()
+			.map_or(2E-4, |n| n.cycle_time as f32 * 1E-6);
 
 		print_raw(&data.1, cycle_time);
 	} else if command["command"] == "ks_sync" {
-		let data = bone.send_ks_sync_command(&command).unwrap();
+		let data = bone.send_ks_sync_command(command)?;
 		duration = start.elapsed().as_millis();
 
-		let cycle_time = {
-			let parsed = bone.send_command(&json::object! {"command" => "ks_cycle_time"});
-
-			match parsed {
-				Ok(n) => match n["payload"]["cycle_time"].as_number() {
-					Some(n) => f32::from(n) * 1E-6,
-					_ => 2E-4,
-				},
-				Err(_err) => 2E-4,
-			}
-		};
+		let cycle_time = bone
+			.ks_cycle_time()
+			.map_or(2E-4, |n| n.cycle_time as f32 * 1E-6);
 
 		print_raw(&data.1, cycle_time);
 	} else if command["command"] == "ks" {
-		let data = bone.send_ks_command(&command).unwrap();
+		let data = bone.send_ks_command(command)?;
 		duration = start.elapsed().as_millis();
 
-		let cycle_time = {
-			let parsed = bone.send_command(&json::object! {"command" => "ks_cycle_time"});
-
-			match parsed {
-				Ok(n) => match n["payload"]["cycle_time"].as_number() {
-					Some(n) => f32::from(n) * 1E-6,
-					_ => 2E-4,
-				},
-				Err(_err) => 2E-4,
-			}
-		};
+		let cycle_time = bone
+			.ks_cycle_time()
+			.map_or(2E-4, |n| n.cycle_time as f32 * 1E-6);
 
 		print_raw(&data.1, cycle_time);
 	} else if command["command"] == "dv_data" {
 		let term_size = get_term_size();
 
-		let data = bone.send_dv_command(&command).unwrap();
+		let data = bone.send_dv_command(command)?;
 		duration = start.elapsed().as_millis();
 
 		Chart::new(term_size.0, term_size.1, 0., data.len() as f32 / 10.)
 			.lineplot(&Shape::Lines(create_xy(&data, 0.1).as_slice()))
 			.nice();
 	} else {
-		let parsed = bone.send_command(&command).unwrap();
+		let parsed = bone.send_command_msgpack(command)?;
 		duration = start.elapsed().as_millis();
 
-		let pretty_response;
-
-		if pretty {
-			pretty_response = json::stringify_pretty(parsed, 4);
-		} else {
-			pretty_response = json::stringify(parsed);
-		}
-
-		println!("{}", pretty_response);
+		println!("{}", msgpack::render(&parsed, pretty, binary));
 	}
 
 	if response_time {
 		writeln_dimmed(&format!("took {} ms", duration)).unwrap();
 	}
+
+	Ok(())
 }
 
 fn writeln_dimmed(output: &str) -> Result<()> {
@@ -548,3 +1321,35 @@ fn print_raw(data: &Vec<(String, Vec<f32>)>, cycle_time: f32) {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn encryption_of(args: &[&str], loopback: bool) -> (bool, bool) {
+		let opt = Opt::parse_from([&["bone_shell"], args].concat());
+		encryption(&opt, loopback)
+	}
+
+	#[test]
+	fn unencrypted_fallback() {
+		// (auto, unencrypted)
+		assert_eq!(encryption_of(&[], false), (true, false));
+		assert_eq!(encryption_of(&[], true), (false, true));
+		assert_eq!(encryption_of(&["--auto"], true), (true, false));
+		assert_eq!(encryption_of(&["--unencrypted"], false), (false, true));
+		assert_eq!(encryption_of(&["-p", "6451"], false), (false, false));
+		assert_eq!(encryption_of(&["-p", "6451"], true), (false, false));
+
+		// explicitly configured TLS is never downgraded
+		for tls_options in [
+			&["--cert", "client.pem"][..],
+			&["--ca-file", "ca.pem"],
+			&["--ca-path", "certs"],
+			&["--insecure"],
+		] {
+			assert_eq!(encryption_of(tls_options, false), (false, false));
+			assert_eq!(encryption_of(tls_options, true), (false, false));
+		}
+	}
+}
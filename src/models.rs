@@ -0,0 +1,404 @@
+//! Typed requests and responses of the known BeMoS commands, for use with
+//! [`crate::Bone::call`] or the shortcuts like [`crate::Bone::date`].
+//!
+//! Other commands are sent by implementing [`Request`] for their payload:
+//!
+//! ```no_run
+//! use bone_api::models::Request;
+//! use bone_api::Bone;
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize)]
+//! struct SetAlias {
+//!     alias: String,
+//! }
+//!
+//! impl Request for SetAlias {
+//!     const COMMAND: &'static str = "set_alias";
+//! }
+//!
+//! #[derive(Deserialize)]
+//! struct Alias {
+//!     alias: String,
+//! }
+//!
+//! let mut bone = Bone::new("192.168.1.10", "6450", false, false);
+//! bone.connect().unwrap();
+//!
+//! let response: Alias = bone.call(&SetAlias { alias: "test bench".into() }).unwrap();
+//! ```
+
+use std::collections::BTreeMap;
+
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+
+use crate::{Bone, BoneError, Result};
+
+/// Payload of a command. Unit structs are sent without payload.
+pub trait Request: Serialize {
+	/// Name of the command.
+	const COMMAND: &'static str;
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct DateRequest;
+
+impl Request for DateRequest {
+	const COMMAND: &'static str = "date";
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Date {
+	/// Local time of the device, e.g. `2021-05-04 21:08:33`.
+	pub date: String,
+	pub timezone: Option<String>,
+	/// Offset of the timezone to UTC, e.g. `+0200`.
+	#[serde(rename = "timezone offset")]
+	pub timezone_offset: Option<String>,
+	/// Whether the clock is synchronized with `timeserver`.
+	pub timesync: Option<bool>,
+	pub timeserver: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct SerialNumberRequest;
+
+impl Request for SerialNumberRequest {
+	const COMMAND: &'static str = "serial_number";
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct SerialNumber {
+	#[serde(deserialize_with = "string_or_number")]
+	pub serial_number: String,
+	pub alias: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct BoardTempRequest;
+
+impl Request for BoardTempRequest {
+	const COMMAND: &'static str = "board_temp";
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct BoardTemp {
+	/// °C
+	#[serde(alias = "temp", alias = "temperature")]
+	pub board_temp: f64,
+}
+
+/// Sample period of `sync` raw data.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct CycleTimeRequest;
+
+impl Request for CycleTimeRequest {
+	const COMMAND: &'static str = "cycle_time";
+}
+
+/// Sample period of `ks` and `ks_sync` raw data.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct KsCycleTimeRequest;
+
+impl Request for KsCycleTimeRequest {
+	const COMMAND: &'static str = "ks_cycle_time";
+}
+
+/// Response of `cycle_time` and `ks_cycle_time`.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub struct CycleTime {
+	/// µs
+	pub cycle_time: f64,
+}
+
+/// Selects the channels of `channel_data` and `channel_attributes`, either one
+/// by `name` or `all` of them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ChannelRequest {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub name: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub all: Option<bool>,
+	/// Include hidden channels.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub hidden: Option<bool>,
+	/// Only channels whose name starts with one of the entries.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub filter: Option<Vec<String>>,
+}
+
+impl ChannelRequest {
+	pub fn name(name: &str) -> ChannelRequest {
+		ChannelRequest {
+			name: Some(name.to_string()),
+			..Default::default()
+		}
+	}
+
+	pub fn all() -> ChannelRequest {
+		ChannelRequest {
+			all: Some(true),
+			..Default::default()
+		}
+	}
+}
+
+/// `channel_data` with a [`ChannelRequest`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct ChannelDataRequest(pub ChannelRequest);
+
+impl Request for ChannelDataRequest {
+	const COMMAND: &'static str = "channel_data";
+}
+
+/// `channel_attributes` with a [`ChannelRequest`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct ChannelAttributesRequest(pub ChannelRequest);
+
+impl Request for ChannelAttributesRequest {
+	const COMMAND: &'static str = "channel_attributes";
+}
+
+/// Response for several channels, with `all` set.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct ChannelList<T> {
+	pub data: Vec<T>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct ChannelValue {
+	pub name: String,
+	pub value: f64,
+	pub unit: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct ChannelAttributes {
+	pub name: String,
+	pub unit: Option<String>,
+	pub hidden: Option<bool>,
+	/// Attributes not covered by the fields above.
+	#[serde(flatten)]
+	pub other: BTreeMap<String, Value>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct RequestTokenRequest;
+
+impl Request for RequestTokenRequest {
+	const COMMAND: &'static str = "request_token";
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Token {
+	pub token: String,
+}
+
+/// Logs in with a token of `request_token` signed with the password, see
+/// [`crate::Bone::login`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct AuthRequest {
+	pub signed_token: String,
+	pub username: String,
+}
+
+impl Request for AuthRequest {
+	const COMMAND: &'static str = "auth";
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Auth {
+	/// User now logged in.
+	pub username: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RemoveUserRequest {
+	pub username: String,
+}
+
+impl Request for RemoveUserRequest {
+	const COMMAND: &'static str = "remove_user";
+}
+
+impl Bone {
+	/// Sends `request` and decodes the payload of the response. An `error` in
+	/// the payload is returned as [`BoneError::Device`].
+	pub fn call<Req: Request, Resp: DeserializeOwned>(&mut self, request: &Req) -> Result<Resp> {
+		let response = self.send_command(&command(request)?)?;
+
+		payload(&response)
+	}
+
+	pub fn date(&mut self) -> Result<Date> {
+		self.call(&DateRequest)
+	}
+
+	pub fn serial_number(&mut self) -> Result<SerialNumber> {
+		self.call(&SerialNumberRequest)
+	}
+
+	pub fn board_temp(&mut self) -> Result<BoardTemp> {
+		self.call(&BoardTempRequest)
+	}
+
+	pub fn cycle_time(&mut self) -> Result<CycleTime> {
+		self.call(&CycleTimeRequest)
+	}
+
+	pub fn ks_cycle_time(&mut self) -> Result<CycleTime> {
+		self.call(&KsCycleTimeRequest)
+	}
+
+	pub fn channel_data(&mut self, name: &str) -> Result<ChannelValue> {
+		self.call(&ChannelDataRequest(ChannelRequest::name(name)))
+	}
+
+	/// Values of all visible channels.
+	pub fn all_channel_data(&mut self) -> Result<Vec<ChannelValue>> {
+		let list: ChannelList<_> = self.call(&ChannelDataRequest(ChannelRequest::all()))?;
+
+		Ok(list.data)
+	}
+
+	pub fn channel_attributes(&mut self, name: &str) -> Result<ChannelAttributes> {
+		self.call(&ChannelAttributesRequest(ChannelRequest::name(name)))
+	}
+
+	/// Attributes of all visible channels.
+	pub fn all_channel_attributes(&mut self) -> Result<Vec<ChannelAttributes>> {
+		let list: ChannelList<_> = self.call(&ChannelAttributesRequest(ChannelRequest::all()))?;
+
+		Ok(list.data)
+	}
+
+	/// Token to sign for [`AuthRequest`], [`Bone::login`] does both steps.
+	pub fn request_token(&mut self) -> Result<Token> {
+		self.call(&RequestTokenRequest)
+	}
+
+	pub fn auth(&mut self, request: &AuthRequest) -> Result<Auth> {
+		self.call(request)
+	}
+
+	pub fn remove_user(&mut self, username: &str) -> Result<()> {
+		let _: IgnoredAny = self.call(&RemoveUserRequest {
+			username: username.to_string(),
+		})?;
+
+		Ok(())
+	}
+}
+
+#[cfg(feature = "async")]
+impl crate::AsyncBone {
+	pub async fn date(&mut self) -> Result<Date> {
+		self.call(&DateRequest).await
+	}
+
+	pub async fn serial_number(&mut self) -> Result<SerialNumber> {
+		self.call(&SerialNumberRequest).await
+	}
+
+	pub async fn board_temp(&mut self) -> Result<BoardTemp> {
+		self.call(&BoardTempRequest).await
+	}
+
+	pub async fn cycle_time(&mut self) -> Result<CycleTime> {
+		self.call(&CycleTimeRequest).await
+	}
+
+	pub async fn ks_cycle_time(&mut self) -> Result<CycleTime> {
+		self.call(&KsCycleTimeRequest).await
+	}
+
+	pub async fn channel_data(&mut self, name: &str) -> Result<ChannelValue> {
+		self.call(&ChannelDataRequest(ChannelRequest::name(name)))
+			.await
+	}
+
+	/// Values of all visible channels.
+	pub async fn all_channel_data(&mut self) -> Result<Vec<ChannelValue>> {
+		let list: ChannelList<_> = self
+			.call(&ChannelDataRequest(ChannelRequest::all()))
+			.await?;
+
+		Ok(list.data)
+	}
+
+	pub async fn channel_attributes(&mut self, name: &str) -> Result<ChannelAttributes> {
+		self.call(&ChannelAttributesRequest(ChannelRequest::name(name)))
+			.await
+	}
+
+	/// Attributes of all visible channels.
+	pub async fn all_channel_attributes(&mut self) -> Result<Vec<ChannelAttributes>> {
+		let list: ChannelList<_> = self
+			.call(&ChannelAttributesRequest(ChannelRequest::all()))
+			.await?;
+
+		Ok(list.data)
+	}
+
+	/// Token to sign for [`AuthRequest`], [`crate::AsyncBone::login`] does both steps.
+	pub async fn request_token(&mut self) -> Result<Token> {
+		self.call(&RequestTokenRequest).await
+	}
+
+	pub async fn auth(&mut self, request: &AuthRequest) -> Result<Auth> {
+		self.call(request).await
+	}
+
+	pub async fn remove_user(&mut self, username: &str) -> Result<()> {
+		let _: IgnoredAny = self
+			.call(&RemoveUserRequest {
+				username: username.to_string(),
+			})
+			.await?;
+
+		Ok(())
+	}
+}
+
+/// Command object for `request`, without payload if there is none.
+pub(crate) fn command<Req: Request>(request: &Req) -> Result<Value> {
+	let mut command = json!({ "command": Req::COMMAND });
+
+	match serde_json::to_value(request)? {
+		Value::Null => {}
+		Value::Object(ref map) if map.is_empty() => {}
+		payload => command["payload"] = payload,
+	}
+
+	Ok(command)
+}
+
+/// Decodes the payload of `response`.
+pub(crate) fn payload<Resp: DeserializeOwned>(response: &Value) -> Result<Resp> {
+	let payload = &response["payload"];
+
+	if let Some(message) = payload["error"].as_str() {
+		return Err(BoneError::Device {
+			message: message.to_string(),
+		});
+	}
+
+	Ok(Resp::deserialize(payload)?)
+}
+
+fn string_or_number<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> std::result::Result<String, D::Error> {
+	match Value::deserialize(deserializer)? {
+		Value::String(s) => Ok(s),
+		Value::Number(n) => Ok(n.to_string()),
+		other => Err(serde::de::Error::custom(format!(
+			"expected string or number, found {}",
+			other
+		))),
+	}
+}
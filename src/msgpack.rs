@@ -0,0 +1,155 @@
+//! Responses in their msgpack types, see [`crate::Bone::send_command_msgpack`].
+//!
+//! [`crate::Bone::send_command`] returns JSON, which has no counterpart for
+//! binary data and ext types, and no room for NaN or infinite floats. The
+//! [`Value`] of a msgpack response keeps all of them.
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+pub use rmpv::Value;
+
+use crate::Result;
+
+/// Encoding of binary data, ext data and invalid strings in [`render`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BinaryFormat {
+	#[default]
+	Base64,
+	Hex,
+}
+
+impl FromStr for BinaryFormat {
+	type Err = String;
+
+	fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+		match s {
+			"base64" => Ok(BinaryFormat::Base64),
+			"hex" => Ok(BinaryFormat::Hex),
+			_ => Err(format!("unknown binary format {}, use base64 or hex", s)),
+		}
+	}
+}
+
+/// Converts a JSON value, integers stay integers.
+pub fn from_json(value: &serde_json::Value) -> Value {
+	match value {
+		serde_json::Value::Null => Value::Nil,
+		serde_json::Value::Bool(b) => Value::from(*b),
+		serde_json::Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+			(Some(n), _) => Value::from(n),
+			(None, Some(n)) => Value::from(n),
+			_ => Value::from(n.as_f64().unwrap_or(f64::NAN)),
+		},
+		serde_json::Value::String(s) => Value::from(s.as_str()),
+		serde_json::Value::Array(array) => Value::Array(array.iter().map(from_json).collect()),
+		serde_json::Value::Object(object) => Value::Map(
+			object
+				.iter()
+				.map(|(key, value)| (Value::from(key.as_str()), from_json(value)))
+				.collect(),
+		),
+	}
+}
+
+/// Renders `value` as JSON where possible. Floats always have a fraction or
+/// exponent, NaN and infinity are written as `NaN`, `Infinity` and `-Infinity`.
+/// Binary data becomes `<bin 4 bytes: AAECAw==>`, ext data `<ext 5, 4 bytes: AAECAw==>`
+/// and strings with invalid UTF-8 `<str 4 bytes, invalid utf-8: AAECAw==>`.
+/// Map keys other than strings are rendered the same way.
+pub fn render(value: &Value, pretty: bool, binary: BinaryFormat) -> String {
+	let mut out = String::new();
+	write_value(&mut out, value, pretty, binary, 0);
+	out
+}
+
+pub(crate) fn decode(buffer: &[u8]) -> Result<Value> {
+	Ok(rmpv::decode::read_value(&mut &buffer[..])?)
+}
+
+fn write_value(out: &mut String, value: &Value, pretty: bool, binary: BinaryFormat, depth: usize) {
+	match value {
+		Value::Nil => out.push_str("null"),
+		Value::Boolean(b) => write!(out, "{}", b).unwrap(),
+		Value::Integer(n) => write!(out, "{}", n).unwrap(),
+		Value::F32(f) => write_float(out, f64::from(*f), &format!("{:?}", f)),
+		Value::F64(f) => write_float(out, *f, &format!("{:?}", f)),
+		Value::String(s) => match s.as_str() {
+			Some(s) => out.push_str(&serde_json::Value::from(s).to_string()),
+			None => write_bytes(out, "str", ", invalid utf-8", s.as_bytes(), binary),
+		},
+		Value::Binary(data) => write_bytes(out, "bin", "", data, binary),
+		Value::Ext(code, data) => write_bytes(out, &format!("ext {},", code), "", data, binary),
+		Value::Array(array) => {
+			let items = array.iter().map(|item| (None, item));
+			write_container(out, ('[', ']'), items, pretty, binary, depth);
+		}
+		Value::Map(map) => {
+			let entries = map.iter().map(|(key, value)| (Some(key), value));
+			write_container(out, ('{', '}'), entries, pretty, binary, depth);
+		}
+	}
+}
+
+fn write_container<'a>(
+	out: &mut String,
+	(open, close): (char, char),
+	items: impl ExactSizeIterator<Item = (Option<&'a Value>, &'a Value)>,
+	pretty: bool,
+	binary: BinaryFormat,
+	depth: usize,
+) {
+	let empty = items.len() == 0;
+	out.push(open);
+
+	for (i, (key, value)) in items.enumerate() {
+		if i > 0 {
+			out.push(',');
+		}
+
+		if pretty {
+			out.push('\n');
+			out.push_str(&"    ".repeat(depth + 1));
+		}
+
+		if let Some(key) = key {
+			write_value(out, key, false, binary, 0);
+			out.push_str(if pretty { ": " } else { ":" });
+		}
+
+		write_value(out, value, pretty, binary, depth + 1);
+	}
+
+	if pretty && !empty {
+		out.push('\n');
+		out.push_str(&"    ".repeat(depth));
+	}
+
+	out.push(close);
+}
+
+fn write_float(out: &mut String, f: f64, digits: &str) {
+	if f.is_nan() {
+		out.push_str("NaN");
+	} else if f.is_infinite() {
+		out.push_str(if f > 0.0 { "Infinity" } else { "-Infinity" });
+	} else {
+		out.push_str(digits);
+	}
+}
+
+fn write_bytes(out: &mut String, kind: &str, note: &str, data: &[u8], binary: BinaryFormat) {
+	let unit = if data.len() == 1 { "byte" } else { "bytes" };
+	write!(out, "<{} {} {}{}: ", kind, data.len(), unit, note).unwrap();
+
+	match binary {
+		BinaryFormat::Base64 => out.push_str(&openssl::base64::encode_block(data)),
+		BinaryFormat::Hex => {
+			for byte in data {
+				write!(out, "{:02x}", byte).unwrap();
+			}
+		}
+	}
+
+	out.push('>');
+}
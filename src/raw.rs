@@ -0,0 +1,169 @@
+//! Decoders for the binary payloads of raw data commands, shared by the
+//! blocking and the async client.
+
+use serde_json::{json, Value};
+
+use crate::{BoneError, RawData, Result};
+
+/// Commands answered with binary raw data instead of a JSON or msgpack response.
+pub(crate) const RAW_COMMANDS: [&str; 4] = ["sync", "ks", "ks_sync", "dv_data"];
+
+/// Whether `command` is answered with raw data, see [`RAW_COMMANDS`].
+pub(crate) fn is_raw_command(command: &Value) -> bool {
+	RAW_COMMANDS.iter().any(|raw| command["command"] == *raw)
+}
+
+/// Channels requested by a `sync` command, in the order of the response.
+pub(crate) fn sync_filter(command: &Value) -> Result<Vec<String>> {
+	let filter: Vec<String> = if let Some(filter) = command["payload"]["filter"].as_array() {
+		filter
+			.iter()
+			.map(|a| match a {
+				Value::String(name) => name.clone(),
+				other => other.to_string(),
+			})
+			.collect()
+	} else {
+		vec![
+			String::from("saw"),
+			String::from("int2"),
+			String::from("coe"),
+			String::from("int"),
+		]
+	};
+
+	if filter.is_empty() {
+		return Err(BoneError::Decode(String::from(
+			"empty filter in sync command",
+		)));
+	}
+
+	Ok(filter)
+}
+
+/// Splits the payload of a `sync` response into equally sized channel blocks.
+pub(crate) fn decode_sync(filter: &[String], buffer: &[u8]) -> RawData {
+	let split_val = buffer.len() / filter.len();
+	let mut pos = 0;
+	let mut ret_vect = Vec::new();
+
+	for current in filter {
+		match &current[..] {
+			"saw" => calc_saw(&buffer[pos..pos + split_val], &mut ret_vect),
+			_ => calc_f32(&buffer[pos..pos + split_val], &mut ret_vect, current),
+		}
+
+		pos += split_val;
+	}
+
+	ret_vect
+}
+
+/// Makes a `ks` command request float samples. Returns the command and the
+/// requested channel.
+pub(crate) fn ks_command(command: &Value) -> (Value, i32) {
+	let mut command = command.clone();
+
+	if let Some(command) = command.as_object_mut() {
+		let payload = command.entry("payload").or_insert_with(|| json!({}));
+
+		if let Some(payload) = payload.as_object_mut() {
+			payload.insert("float".to_string(), Value::Bool(true));
+		}
+	}
+
+	let channel = command["payload"]["channel"]
+		.as_i64()
+		.and_then(|channel| i32::try_from(channel).ok())
+		.unwrap_or(0);
+
+	(command, channel)
+}
+
+pub(crate) fn decode_ks(channel: i32, buffer: &[u8]) -> RawData {
+	let mut ret_vect = Vec::new();
+
+	calc_f32(buffer, &mut ret_vect, &format!("channel {}", channel));
+
+	ret_vect
+}
+
+pub(crate) fn decode_ks_sync(buffer: &[u8]) -> Result<RawData> {
+	let mut temp: [Vec<f32>; 8] = Default::default();
+
+	for chunk in buffer.chunks_exact(5) {
+		let channel: usize = chunk[0] as usize;
+		let data = u32::from_be_bytes([chunk[1], chunk[2], chunk[3], chunk[4]]);
+
+		match temp.get_mut(channel) {
+			Some(values) => values.push(f32::from_bits(data)),
+			None => {
+				return Err(BoneError::Decode(format!(
+					"invalid ks channel {} in response",
+					channel
+				)))
+			}
+		}
+	}
+
+	let mut output_vec = Vec::new();
+
+	for (i, x) in temp.into_iter().enumerate() {
+		if !x.is_empty() {
+			output_vec.push((format!("channel {}", i), x));
+		}
+	}
+
+	Ok(output_vec)
+}
+
+pub(crate) fn decode_dv(buffer: &[u8]) -> Result<Vec<f32>> {
+	let mut out = Vec::new();
+	for chunk in buffer.chunks_exact(3) {
+		let s = std::str::from_utf8(chunk).map_err(|e| BoneError::Decode(e.to_string()))?;
+		let dv = usize::from_str_radix(s, 16)
+			.map_err(|e| BoneError::Decode(format!("invalid dv sample {:?}: {}", s, e)))?;
+		let dv = (dv as f32 - 2048.) / 4096. * 5.;
+		out.push(dv);
+	}
+
+	Ok(out)
+}
+
+fn calc_saw(buffer: &[u8], output_vec: &mut RawData) {
+	let mut rt_buf: Vec<f32> = Vec::new();
+	let mut amp_buf: Vec<f32> = Vec::new();
+
+	for chunk in buffer.chunks_exact(4) {
+		let data = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+
+		let mut runtime: f32 = ((data & 0xfffff000) >> 12) as f32;
+		runtime /= 521.0;
+		runtime *= 100.0;
+
+		let mut amplitude: f32 = (data & 0x00000fff) as f32;
+
+		amplitude /= 4096.0;
+		amplitude *= 5.0;
+		amplitude -= 2.5;
+		amplitude *= 2.0;
+
+		rt_buf.push(runtime);
+		amp_buf.push(amplitude);
+	}
+
+	output_vec.push(("rt".to_string(), rt_buf));
+	output_vec.push(("amp".to_string(), amp_buf));
+}
+
+fn calc_f32(buffer: &[u8], output_vec: &mut RawData, name: &str) {
+	let mut temp: Vec<f32> = Vec::new();
+
+	for chunk in buffer.chunks_exact(4) {
+		let data = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+
+		temp.push(f32::from_bits(data));
+	}
+
+	output_vec.push((name.to_string(), temp));
+}
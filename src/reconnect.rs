@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::BoneError;
+
+/// How [`crate::Bone`] recovers from a broken connection, see
+/// [`crate::Bone::set_reconnect_policy`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+	/// Connection attempts before giving up.
+	pub max_attempts: u32,
+	/// Wait after the first failed attempt, doubled after every further one.
+	pub initial_backoff: Duration,
+	pub max_backoff: Duration,
+	/// Commands that are sent again after reconnecting. Others fail with the
+	/// error that broke the connection, as they may have reached the device.
+	pub idempotent_commands: Vec<String>,
+}
+
+impl Default for ReconnectPolicy {
+	fn default() -> Self {
+		ReconnectPolicy {
+			max_attempts: 5,
+			initial_backoff: Duration::from_millis(500),
+			max_backoff: Duration::from_secs(8),
+			idempotent_commands: [
+				"serial_number",
+				"date",
+				"cycle_time",
+				"ks_cycle_time",
+				"board_temp",
+				"channel_data",
+				"channel_attributes",
+				"sync",
+				"sync_json",
+				"ks",
+				"ks_sync",
+				"dv_data",
+			]
+			.iter()
+			.map(|command| command.to_string())
+			.collect(),
+		}
+	}
+}
+
+impl ReconnectPolicy {
+	pub(crate) fn is_idempotent(&self, command: &Value) -> bool {
+		command["command"]
+			.as_str()
+			.is_some_and(|name| self.idempotent_commands.iter().any(|c| c == name))
+	}
+
+	/// Wait after `attempt` failed attempts.
+	pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+		self.initial_backoff
+			.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+			.min(self.max_backoff)
+	}
+}
+
+/// Progress of an automatic reconnect, passed to the handler set with
+/// [`crate::Bone::set_reconnect_handler`].
+#[derive(Debug)]
+pub enum ReconnectEvent<'a> {
+	/// The connection broke and is reopened.
+	Lost(&'a BoneError),
+	/// Attempt number `attempt` failed, the next one follows after `delay`.
+	Retrying {
+		attempt: u32,
+		delay: Duration,
+		error: &'a BoneError,
+	},
+	/// The connection is open again.
+	Reconnected { attempts: u32 },
+	/// The last login was replayed on the new connection.
+	Reauthenticated { username: &'a str },
+	/// Reconnecting failed, the client stays disconnected.
+	GaveUp(&'a BoneError),
+}
+
+pub(crate) type ReconnectHandler = Box<dyn FnMut(&ReconnectEvent) + Send>;
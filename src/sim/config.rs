@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Shape of a synthetic signal.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Shape {
+	Constant,
+	Sine,
+	Square,
+	Triangle,
+	Sawtooth,
+	Noise,
+}
+
+/// Synthetic signal, evaluated at the time of each sample.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Waveform {
+	pub shape: Shape,
+	/// Hz
+	pub frequency: f64,
+	pub amplitude: f64,
+	pub offset: f64,
+	/// Amplitude of uniform noise added on top of the shape.
+	pub noise: f64,
+	pub unit: String,
+}
+
+impl Default for Waveform {
+	fn default() -> Self {
+		Waveform {
+			shape: Shape::Sine,
+			frequency: 50.,
+			amplitude: 1.,
+			offset: 0.,
+			noise: 0.,
+			unit: String::new(),
+		}
+	}
+}
+
+impl Waveform {
+	pub fn sample(&self, t: f64, rng: &mut Rng) -> f64 {
+		let phase = (t * self.frequency).fract();
+
+		let value = match self.shape {
+			Shape::Constant => 1.,
+			Shape::Sine => (2. * PI * phase).sin(),
+			Shape::Square => {
+				if phase < 0.5 {
+					1.
+				} else {
+					-1.
+				}
+			}
+			Shape::Triangle => 1. - 4. * (phase - 0.5).abs(),
+			Shape::Sawtooth => 2. * phase - 1.,
+			Shape::Noise => rng.next(),
+		};
+
+		self.offset + self.amplitude * value + self.noise * rng.next()
+	}
+}
+
+/// Runtime and amplitude of the `saw` samples of `sync`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Saw {
+	/// Runtime, 0 to ~200000 in the units `calc_saw` decodes
+	pub runtime: Waveform,
+	/// Amplitude, -5 to 5
+	pub amplitude: Waveform,
+}
+
+impl Default for Saw {
+	fn default() -> Self {
+		Saw {
+			runtime: Waveform {
+				shape: Shape::Constant,
+				amplitude: 1000.,
+				noise: 5.,
+				..Default::default()
+			},
+			amplitude: Waveform {
+				frequency: 10.,
+				amplitude: 2.,
+				..Default::default()
+			},
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+	pub serial_number: String,
+	pub alias: Option<String>,
+	/// µs between two raw samples
+	pub cycle_time: u32,
+	/// µs between two ks samples
+	pub ks_cycle_time: u32,
+	/// Number of samples in each raw data response
+	pub samples: usize,
+	pub saw: Saw,
+	/// Raw data channels of `sync`, besides `saw`
+	pub channels: BTreeMap<String, Waveform>,
+	/// Channels of `ks` and `ks_sync`, up to 8
+	pub ks_channels: Vec<Waveform>,
+	/// Signal of `dv_data`, -2.5 to 2.5
+	pub dv: Waveform,
+	/// Canned payloads, answered as they are instead of the generated ones
+	pub responses: BTreeMap<String, Value>,
+	/// Passwords of the users that can log in
+	pub users: BTreeMap<String, String>,
+	/// Commands answered only after a login
+	pub login_required: Vec<String>,
+	/// Seconds without a command after which a login expires
+	pub session_timeout: f64,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		let channel = |frequency, amplitude, unit: &str| Waveform {
+			frequency,
+			amplitude,
+			noise: amplitude / 50.,
+			unit: unit.to_string(),
+			..Default::default()
+		};
+
+		Config {
+			serial_number: String::from("1"),
+			alias: Some(String::from("bone_sim")),
+			cycle_time: 200,
+			ks_cycle_time: 200,
+			samples: 1000,
+			saw: Saw::default(),
+			channels: BTreeMap::from([
+				(String::from("int2"), channel(50., 1., "V")),
+				(String::from("coe"), channel(25., 0.5, "")),
+				(String::from("int"), channel(50., 2., "V")),
+			]),
+			ks_channels: vec![channel(50., 1., "g"), channel(100., 0.5, "g")],
+			dv: Waveform {
+				frequency: 5.,
+				amplitude: 2.,
+				..Default::default()
+			},
+			responses: BTreeMap::new(),
+			users: BTreeMap::from([(String::from("admin"), String::from("admin"))]),
+			login_required: vec![String::from("add_user"), String::from("remove_user")],
+			session_timeout: 600.,
+		}
+	}
+}
+
+impl Config {
+	pub fn load(path: &Path) -> Result<Config, String> {
+		let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+		serde_json::from_str(&content).map_err(|e| e.to_string())
+	}
+}
+
+/// Small xorshift generator for the noise of synthetic signals.
+pub struct Rng(u64);
+
+impl Rng {
+	pub fn new(seed: u64) -> Rng {
+		Rng(seed | 1)
+	}
+
+	/// Uniform value in [-1, 1)
+	pub fn next(&mut self) -> f64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+
+		(self.0 >> 11) as f64 / (1u64 << 52) as f64 - 1.
+	}
+}
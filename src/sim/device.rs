@@ -0,0 +1,400 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bone_api::testing::{self, MockResponse};
+use bone_api::SESSION_EXPIRED_ERROR;
+use serde_json::{json, Value};
+
+use crate::config::{Config, Rng, Waveform};
+
+/// Simulated device answering commands from its config.
+pub struct Device {
+	config: Config,
+	start: Instant,
+}
+
+/// Login state of one connection.
+#[derive(Default)]
+pub struct Session {
+	token: String,
+	/// Logged in user and the time of the last command
+	user: Option<(String, Instant)>,
+}
+
+impl Device {
+	pub fn new(config: Config) -> Device {
+		Device {
+			config,
+			start: Instant::now(),
+		}
+	}
+
+	pub fn respond(&self, request: &Value, session: &mut Session, rng: &mut Rng) -> MockResponse {
+		let command = request["command"].as_str().unwrap_or_default();
+		let payload = &request["payload"];
+		let login_required = self.config.login_required.iter().any(|c| c == command);
+
+		if !self.logged_in(session) && login_required {
+			return MockResponse::json(json!({ "error": SESSION_EXPIRED_ERROR }));
+		}
+
+		if let Some(canned) = self.config.responses.get(command) {
+			return MockResponse::json(canned.clone());
+		}
+
+		match command {
+			"request_token" => {
+				session.token = new_token();
+				MockResponse::json(json!({ "token": session.token }))
+			}
+			"auth" => self.auth(payload, session),
+			"serial_number" => MockResponse::json(json!({
+				"serial_number": self.config.serial_number,
+				"alias": self.config.alias,
+			})),
+			"date" => MockResponse::json(json!({
+				"date": format_date(SystemTime::now()),
+				"timezone": "UTC",
+				"timezone offset": "+0000",
+				"timesync": false,
+			})),
+			"cycle_time" => MockResponse::json(json!({ "cycle_time": self.config.cycle_time })),
+			"ks_cycle_time" => {
+				MockResponse::json(json!({ "cycle_time": self.config.ks_cycle_time }))
+			}
+			"channel_data" => self.channel_data(payload, rng),
+			"channel_attributes" => self.channel_attributes(payload),
+			"sync" => self.sync(payload, rng),
+			"ks" => self.ks(payload, rng),
+			"ks_sync" => self.ks_sync(rng),
+			"dv_data" => self.dv_data(rng),
+			_ if login_required => MockResponse::json(json!({})),
+			_ => MockResponse::json(json!({ "error": "unknown command" })),
+		}
+	}
+
+	/// Whether a user is logged in on the connection, which counts as activity.
+	/// Logs the user out after `session_timeout` seconds without a command.
+	fn logged_in(&self, session: &mut Session) -> bool {
+		let timeout = Duration::from_secs_f64(self.config.session_timeout.max(0.));
+
+		match session.user {
+			Some((_, ref mut last_command)) if last_command.elapsed() <= timeout => {
+				*last_command = Instant::now();
+				true
+			}
+			_ => {
+				session.user = None;
+				false
+			}
+		}
+	}
+
+	fn auth(&self, payload: &Value, session: &mut Session) -> MockResponse {
+		let username = payload["username"].as_str().unwrap_or_default();
+		let signed = payload["signed_token"].as_str().unwrap_or_default();
+
+		match self.config.users.get(username) {
+			Some(password)
+				if !session.token.is_empty()
+					&& testing::signed_token(password, &session.token) == signed =>
+			{
+				session.user = Some((username.to_string(), Instant::now()));
+				MockResponse::json(json!({ "username": username }))
+			}
+			_ => MockResponse::json(json!({ "error": "wrong username or password" })),
+		}
+	}
+
+	/// Index of the newest sample and the times of the `samples` samples up to it.
+	fn sample_times(&self, cycle_time: u32) -> (i32, Vec<f64>) {
+		let cycle_time = cycle_time.max(1) as u128;
+		let end = self.start.elapsed().as_micros() / cycle_time;
+
+		let times = (0..self.config.samples as u128)
+			.map(|i| (end + i).saturating_sub(self.config.samples as u128))
+			.map(|n| (n * cycle_time) as f64 * 1E-6)
+			.collect();
+
+		(end as i32, times)
+	}
+
+	fn channel_data(&self, payload: &Value, rng: &mut Rng) -> MockResponse {
+		let t = self.start.elapsed().as_secs_f64();
+		let entry = |name: &str, waveform: &Waveform, rng: &mut Rng| {
+			json!({
+				"name": name,
+				"value": waveform.sample(t, rng),
+				"unit": waveform.unit,
+			})
+		};
+
+		if payload["all"].as_bool() == Some(true) {
+			let data: Vec<Value> = self
+				.config
+				.channels
+				.iter()
+				.map(|(name, waveform)| entry(name, waveform, rng))
+				.collect();
+
+			return MockResponse::json(json!({ "data": data }));
+		}
+
+		let name = payload["name"].as_str().unwrap_or_default();
+
+		match self.config.channels.get(name) {
+			Some(waveform) => MockResponse::json(entry(name, waveform, rng)),
+			None => MockResponse::json(json!({ "error": "unknown channel" })),
+		}
+	}
+
+	fn channel_attributes(&self, payload: &Value) -> MockResponse {
+		let entry = |name: &str, waveform: &Waveform| {
+			json!({
+				"name": name,
+				"unit": waveform.unit,
+				"hidden": false,
+			})
+		};
+
+		if payload["all"].as_bool() == Some(true) {
+			let data: Vec<Value> = self
+				.config
+				.channels
+				.iter()
+				.map(|(name, waveform)| entry(name, waveform))
+				.collect();
+
+			return MockResponse::json(json!({ "data": data }));
+		}
+
+		let name = payload["name"].as_str().unwrap_or_default();
+
+		match self.config.channels.get(name) {
+			Some(waveform) => MockResponse::json(entry(name, waveform)),
+			None => MockResponse::json(json!({ "error": "unknown channel" })),
+		}
+	}
+
+	fn sync(&self, payload: &Value, rng: &mut Rng) -> MockResponse {
+		let filter: Vec<&str> = match payload["filter"].as_array() {
+			Some(filter) => filter.iter().filter_map(|f| f.as_str()).collect(),
+			None => vec!["saw", "int2", "coe", "int"],
+		};
+
+		let (position, times) = self.sample_times(self.config.cycle_time);
+		let mut data = Vec::new();
+
+		for name in filter {
+			if name == "saw" {
+				for &t in &times {
+					let runtime = self.config.saw.runtime.sample(t, rng);
+					let amplitude = self.config.saw.amplitude.sample(t, rng);
+
+					data.extend_from_slice(&pack_saw(runtime, amplitude).to_be_bytes());
+				}
+			} else {
+				let waveform = self.config.channels.get(name);
+
+				for &t in &times {
+					let value = waveform.map_or(0., |w| w.sample(t, rng)) as f32;
+					data.extend_from_slice(&value.to_be_bytes());
+				}
+			}
+		}
+
+		MockResponse::Positioned(position, data)
+	}
+
+	fn ks(&self, payload: &Value, rng: &mut Rng) -> MockResponse {
+		let channel = payload["channel"].as_u64().unwrap_or(0) as usize;
+		let waveform = self.config.ks_channels.get(channel);
+
+		let (position, times) = self.sample_times(self.config.ks_cycle_time);
+		let mut data = Vec::new();
+
+		for t in times {
+			let value = waveform.map_or(0., |w| w.sample(t, rng)) as f32;
+			data.extend_from_slice(&value.to_be_bytes());
+		}
+
+		MockResponse::Positioned(position, data)
+	}
+
+	fn ks_sync(&self, rng: &mut Rng) -> MockResponse {
+		let (position, times) = self.sample_times(self.config.ks_cycle_time);
+		let mut data = Vec::new();
+
+		for t in times {
+			for (channel, waveform) in self.config.ks_channels.iter().take(8).enumerate() {
+				data.push(channel as u8);
+				data.extend_from_slice(&(waveform.sample(t, rng) as f32).to_be_bytes());
+			}
+		}
+
+		MockResponse::Positioned(position, data)
+	}
+
+	fn dv_data(&self, rng: &mut Rng) -> MockResponse {
+		let data = (0..self.config.samples)
+			.map(|i| self.config.dv.sample(i as f64 * 1E-4, rng))
+			.map(|dv| format!("{:03x}", pack_dv(dv)))
+			.collect::<String>();
+
+		MockResponse::Plain(data.into_bytes())
+	}
+}
+
+fn new_token() -> String {
+	let mut buf = [0; 32];
+	openssl::rand::rand_bytes(&mut buf).unwrap_or_default();
+	hex::encode(buf)
+}
+
+/// Packs a saw sample the way `raw::calc_saw` unpacks it: 20 bit runtime in
+/// units of 100/521, followed by a 12 bit amplitude spanning -5 to 5.
+fn pack_saw(runtime: f64, amplitude: f64) -> u32 {
+	let runtime = (runtime * 521. / 100.).round().clamp(0., 0xfffff as f64) as u32;
+	let amplitude = ((amplitude / 2. + 2.5) / 5. * 4096.)
+		.round()
+		.clamp(0., 0xfff as f64) as u32;
+
+	(runtime << 12) | amplitude
+}
+
+/// Packs a DirectView sample the way `raw::decode_dv` unpacks it: 12 bit value
+/// spanning -2.5 to 2.5.
+fn pack_dv(dv: f64) -> u32 {
+	(dv / 5. * 4096. + 2048.).round().clamp(0., 0xfff as f64) as u32
+}
+
+/// Formats a time as `YYYY-MM-DD HH:MM:SS` in UTC.
+fn format_date(time: SystemTime) -> String {
+	let secs = time
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs();
+	let (days, secs) = (secs / 86400, secs % 86400);
+
+	// civil from days, see http://howardhinnant.github.io/date_algorithms.html
+	let z = days as i64 + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z.rem_euclid(146097);
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	format!(
+		"{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+		year,
+		month,
+		day,
+		secs / 3600,
+		secs % 3600 / 60,
+		secs % 60
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::TcpListener;
+	use std::sync::Arc;
+	use std::thread;
+	use std::time::Duration;
+
+	use bone_api::testing::{MockResponse, MockServer};
+	use bone_api::{Bone, BoneError};
+	use serde_json::json;
+
+	use super::{pack_dv, pack_saw, Device};
+	use crate::config::Config;
+
+	fn assert_close(decoded: &[f32], expected: &[f64], step: f64) {
+		assert_eq!(decoded.len(), expected.len());
+
+		for (&decoded, &expected) in decoded.iter().zip(expected) {
+			assert!(
+				(decoded as f64 - expected).abs() <= step / 2.,
+				"decoded {} instead of {}",
+				decoded,
+				expected
+			);
+		}
+	}
+
+	/// The packed samples decode to the simulated values through the client.
+	#[test]
+	fn raw_data_round_trip() {
+		let runtimes = [0., 12.5, 1000., 200000.];
+		let amplitudes = [-5., -1.25, 0., 4.99];
+		let dvs = [-2.5, -0.3, 0., 2.49];
+
+		let saw = runtimes
+			.iter()
+			.zip(&amplitudes)
+			.flat_map(|(&runtime, &amplitude)| pack_saw(runtime, amplitude).to_be_bytes())
+			.collect();
+		let dv = dvs
+			.iter()
+			.map(|&dv| format!("{:03x}", pack_dv(dv)))
+			.collect::<String>();
+
+		let server = MockServer::start().unwrap();
+		server.respond("sync", MockResponse::Positioned(0, saw));
+		server.respond("dv_data", MockResponse::Plain(dv.into_bytes()));
+
+		let mut bone = Bone::new(&server.ip(), &server.port().to_string(), false, false);
+		bone.connect().unwrap();
+
+		let (_, data) = bone
+			.send_sync_command(&json!({"command": "sync", "payload": {"filter": ["saw"]}}))
+			.unwrap();
+		assert_eq!(data[0].0, "rt");
+		assert_close(&data[0].1, &runtimes, 100. / 521.);
+		assert_eq!(data[1].0, "amp");
+		assert_close(&data[1].1, &amplitudes, 10. / 4096.);
+
+		let data = bone
+			.send_dv_command(&json!({"command": "dv_data"}))
+			.unwrap();
+		assert_close(&data, &dvs, 5. / 4096.);
+	}
+
+	/// Commands that need a login fail with the error the client takes for an
+	/// expired session, before logging in and after the login timed out.
+	#[test]
+	fn session_timeout() {
+		let device = Device::new(Config {
+			session_timeout: 0.2,
+			..Default::default()
+		});
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		thread::spawn(move || crate::accept(listener, Arc::new(device), None));
+
+		let mut bone = Bone::new("127.0.0.1", &port.to_string(), false, false);
+		bone.connect().unwrap();
+
+		let add_user = json!({"command": "add_user", "payload": {"username": "guest"}});
+		assert!(matches!(
+			bone.send_command(&add_user),
+			Err(BoneError::SessionExpired(_))
+		));
+
+		bone.login("admin", "admin").unwrap();
+		let response = bone.send_command(&add_user).unwrap();
+		assert!(response["payload"]["error"].is_null());
+
+		thread::sleep(Duration::from_millis(300));
+		assert!(matches!(
+			bone.send_command(&add_user),
+			Err(BoneError::SessionExpired(_))
+		));
+
+		// commands without login are still answered
+		let response = bone.send_command(&json!({"command": "date"})).unwrap();
+		assert!(response["payload"]["date"].is_string());
+	}
+}
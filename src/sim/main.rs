@@ -0,0 +1,227 @@
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::Parser;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509};
+
+use bone_api::testing;
+
+mod config;
+mod device;
+
+use config::{Config, Rng};
+use device::{Device, Session};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Simulates a BeMoS device on localhost
+#[derive(Parser, Debug)]
+#[command(version = VERSION, about, long_about = None)]
+pub struct Opt {
+	#[arg(long)]
+	config: Option<PathBuf>,
+
+	#[arg(long, default_values = ["127.0.0.1", "::1"])]
+	bind: Vec<String>,
+
+	#[arg(long, default_value = "6450")]
+	port: u16,
+
+	#[arg(long, default_value = "6451")]
+	tls_port: u16,
+
+	#[arg(long)]
+	no_tls: bool,
+
+	#[arg(long)]
+	cert: Option<PathBuf>,
+
+	#[arg(long)]
+	key: Option<PathBuf>,
+}
+
+fn main() {
+	let opt = Opt::parse();
+
+	let config = match &opt.config {
+		Some(path) => match Config::load(path) {
+			Ok(config) => config,
+			Err(e) => {
+				eprintln!("Error loading {}: {}", path.display(), e);
+				std::process::exit(1)
+			}
+		},
+		None => Config::default(),
+	};
+
+	let acceptor = if opt.no_tls {
+		None
+	} else {
+		match build_acceptor(&opt, &config.serial_number) {
+			Ok(acceptor) => Some(Arc::new(acceptor)),
+			Err(e) => {
+				eprintln!("Error setting up TLS: {}", e);
+				std::process::exit(1)
+			}
+		}
+	};
+
+	let device = Arc::new(Device::new(config));
+	let mut handles = Vec::new();
+
+	for ip in &opt.bind {
+		let mut ports = vec![(opt.port, None)];
+		if let Some(ref acceptor) = acceptor {
+			ports.push((opt.tls_port, Some(acceptor.clone())));
+		}
+
+		for (port, acceptor) in ports {
+			let listener = match TcpListener::bind((ip.as_str(), port)) {
+				Ok(listener) => listener,
+				Err(e) => {
+					eprintln!("Could not listen on [{}]:{}: {}", ip, port, e);
+					continue;
+				}
+			};
+
+			println!(
+				"Listening on [{}]:{} ({})",
+				ip,
+				port,
+				if acceptor.is_some() {
+					"encrypted"
+				} else {
+					"unencrypted"
+				}
+			);
+
+			let device = device.clone();
+			handles.push(thread::spawn(move || accept(listener, device, acceptor)));
+		}
+	}
+
+	if handles.is_empty() {
+		std::process::exit(1)
+	}
+
+	for handle in handles {
+		let _ = handle.join();
+	}
+}
+
+fn accept(listener: TcpListener, device: Arc<Device>, acceptor: Option<Arc<SslAcceptor>>) {
+	for stream in listener.incoming().flatten() {
+		let device = device.clone();
+		let acceptor = acceptor.clone();
+
+		thread::spawn(move || serve(stream, device, acceptor));
+	}
+}
+
+fn serve(stream: TcpStream, device: Arc<Device>, acceptor: Option<Arc<SslAcceptor>>) {
+	// answer pipelined requests without waiting for the previous response to be acknowledged
+	let _ = stream.set_nodelay(true);
+
+	let peer = stream
+		.peer_addr()
+		.map(|a| a.to_string())
+		.unwrap_or_default();
+
+	let seed = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_nanos() as u64;
+	let mut rng = Rng::new(seed);
+
+	let mut session = Session::default();
+
+	let handler = |request: &serde_json::Value| {
+		println!("{}: {}", peer, request);
+		device.respond(request, &mut session, &mut rng)
+	};
+
+	match acceptor {
+		Some(acceptor) => match acceptor.accept(stream) {
+			Ok(stream) => testing::serve(stream, handler),
+			Err(e) => eprintln!("{}: TLS handshake failed: {}", peer, e),
+		},
+		None => testing::serve(stream, handler),
+	}
+}
+
+fn build_acceptor(
+	opt: &Opt,
+	serial_number: &str,
+) -> Result<SslAcceptor, openssl::error::ErrorStack> {
+	let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+
+	match (&opt.cert, &opt.key) {
+		(Some(cert), key) => {
+			builder.set_certificate_chain_file(cert)?;
+			builder.set_private_key_file(key.as_ref().unwrap_or(cert), SslFiletype::PEM)?;
+		}
+		(None, _) => {
+			let (cert, key) = self_signed_certificate(serial_number)?;
+			println!(
+				"Using self-signed certificate sha256:{}",
+				hex::encode(cert.digest(MessageDigest::sha256())?)
+			);
+
+			builder.set_certificate(&cert)?;
+			builder.set_private_key(&key)?;
+		}
+	}
+
+	builder.check_private_key()?;
+
+	Ok(builder.build())
+}
+
+/// Certificate for the serial number and localhost, like the ones devices ship with.
+fn self_signed_certificate(
+	serial_number: &str,
+) -> Result<(X509, PKey<Private>), openssl::error::ErrorStack> {
+	let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+
+	let mut name = X509NameBuilder::new()?;
+	name.append_entry_by_text("CN", serial_number)?;
+	let name = name.build();
+
+	let mut serial = BigNum::new()?;
+	serial.rand(64, MsbOption::MAYBE_ZERO, false)?;
+
+	let mut builder = X509::builder()?;
+	builder.set_version(2)?;
+	let serial = serial.to_asn1_integer()?;
+	let not_before = Asn1Time::days_from_now(0)?;
+	let not_after = Asn1Time::days_from_now(365)?;
+
+	builder.set_serial_number(&serial)?;
+	builder.set_subject_name(&name)?;
+	builder.set_issuer_name(&name)?;
+	builder.set_pubkey(&key)?;
+	builder.set_not_before(&not_before)?;
+	builder.set_not_after(&not_after)?;
+
+	let alt_names = SubjectAlternativeName::new()
+		.dns(serial_number)
+		.dns("localhost")
+		.ip("127.0.0.1")
+		.ip("::1")
+		.build(&builder.x509v3_context(None, None))?;
+	builder.append_extension(alt_names)?;
+
+	builder.sign(&key, MessageDigest::sha256())?;
+
+	Ok((builder.build(), key))
+}
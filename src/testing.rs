@@ -0,0 +1,380 @@
+//! In-process stand-in for a BeMoS device, to test code using [`crate::Bone`]
+//! without hardware. Only available with the `testing` feature.
+//!
+//! ```no_run
+//! use bone_api::testing::{MockResponse, MockServer};
+//! use bone_api::Bone;
+//!
+//! let server = MockServer::start().unwrap();
+//! server.respond("date", MockResponse::json(serde_json::json!({"date": "2021-05-04 21:08:33"})));
+//! server.add_user("admin", "secret");
+//!
+//! let mut bone = Bone::new(&server.ip(), &server.port().to_string(), false, false);
+//! bone.connect().unwrap();
+//! bone.login("admin", "secret").unwrap();
+//! ```
+
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use openssl::ssl::SslAcceptor;
+use serde_json::{json, Value};
+
+use crate::codec::{self, Frame};
+use crate::Bone;
+
+/// Scripted answer to a command.
+#[derive(Clone, Debug)]
+pub enum MockResponse {
+	/// A JSON response, sent in the encoding of the request.
+	Json(Value),
+	/// A plain frame with the given payload, e.g. `dv_data`.
+	Plain(Vec<u8>),
+	/// A frame with buffer position and payload, e.g. `sync`, `ks` and `ks_sync`.
+	Positioned(i32, Vec<u8>),
+	/// Bytes written as they are, without framing.
+	Raw(Vec<u8>),
+	/// Read the request but never answer.
+	Silent,
+	/// Close the connection instead of answering.
+	Close,
+}
+
+impl MockResponse {
+	/// Wraps `payload` into the `{"command": ..., "payload": ...}` envelope of
+	/// device responses. A missing command name is filled in when answering.
+	pub fn json(payload: Value) -> MockResponse {
+		MockResponse::Json(json!({ "payload": payload }))
+	}
+}
+
+#[derive(Default)]
+struct State {
+	persistent: HashMap<String, MockResponse>,
+	queued: HashMap<String, VecDeque<MockResponse>>,
+	users: HashMap<String, String>,
+	requests: Vec<Value>,
+	connections: usize,
+	read_before_response: usize,
+	read_delay: Option<Duration>,
+}
+
+/// Listens on a local port and answers commands with scripted responses. Shuts
+/// down when dropped.
+pub struct MockServer {
+	addr: SocketAddr,
+	state: Arc<Mutex<State>>,
+	shutdown: Arc<AtomicBool>,
+	handle: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+	/// Starts listening on a free port of 127.0.0.1.
+	pub fn start() -> std::io::Result<MockServer> {
+		MockServer::listen(None)
+	}
+
+	/// Like [`MockServer::start`], but runs a TLS handshake with `acceptor` on
+	/// every connection first.
+	pub fn start_tls(acceptor: SslAcceptor) -> std::io::Result<MockServer> {
+		MockServer::listen(Some(acceptor))
+	}
+
+	fn listen(acceptor: Option<SslAcceptor>) -> std::io::Result<MockServer> {
+		let listener = TcpListener::bind("127.0.0.1:0")?;
+		let addr = listener.local_addr()?;
+
+		let state = Arc::new(Mutex::new(State::default()));
+		let shutdown = Arc::new(AtomicBool::new(false));
+
+		let handle = {
+			let state = state.clone();
+			let shutdown = shutdown.clone();
+
+			thread::spawn(move || {
+				for stream in listener.incoming() {
+					if shutdown.load(Ordering::SeqCst) {
+						break;
+					}
+
+					if let Ok(stream) = stream {
+						let _ = stream.set_nodelay(true);
+						state.lock().unwrap().connections += 1;
+
+						let state = state.clone();
+						let acceptor = acceptor.clone();
+
+						thread::spawn(move || {
+							let read_delay = state.lock().unwrap().read_delay;
+							if let Some(delay) = read_delay {
+								thread::sleep(delay);
+							}
+
+							match acceptor {
+								Some(acceptor) => {
+									if let Ok(stream) = acceptor.accept(stream) {
+										serve_mock(stream, state);
+									}
+								}
+								None => serve_mock(stream, state),
+							}
+						});
+					}
+				}
+			})
+		};
+
+		Ok(MockServer {
+			addr,
+			state,
+			shutdown,
+			handle: Some(handle),
+		})
+	}
+
+	pub fn addr(&self) -> SocketAddr {
+		self.addr
+	}
+
+	pub fn ip(&self) -> String {
+		self.addr.ip().to_string()
+	}
+
+	pub fn port(&self) -> u16 {
+		self.addr.port()
+	}
+
+	/// Answers every request for `command` with `response`.
+	pub fn respond(&self, command: &str, response: MockResponse) {
+		let mut state = self.state.lock().unwrap();
+		state.persistent.insert(command.to_string(), response);
+	}
+
+	/// Answers the next request for `command` with `response`. Queued responses
+	/// are used up in order before falling back to the one set with `respond`.
+	pub fn respond_once(&self, command: &str, response: MockResponse) {
+		let mut state = self.state.lock().unwrap();
+		state
+			.queued
+			.entry(command.to_string())
+			.or_default()
+			.push_back(response);
+	}
+
+	/// Adds a user that can log in with the `request_token`/`auth` handshake.
+	pub fn add_user(&self, username: &str, password: &str) {
+		let mut state = self.state.lock().unwrap();
+		state
+			.users
+			.insert(username.to_string(), password.to_string());
+	}
+
+	/// All commands received so far, from all connections.
+	pub fn requests(&self) -> Vec<Value> {
+		self.state.lock().unwrap().requests.clone()
+	}
+
+	/// Number of connections accepted so far.
+	pub fn connections(&self) -> usize {
+		self.state.lock().unwrap().connections
+	}
+
+	/// Most requests read from one connection before the first response was
+	/// written to it, more than one if the client pipelines its requests.
+	pub fn requests_before_first_response(&self) -> usize {
+		self.state.lock().unwrap().read_before_response
+	}
+
+	/// Waits `delay` after accepting a connection before reading from it, so that
+	/// requests sent without waiting for responses arrive together.
+	pub fn set_read_delay(&self, delay: Option<Duration>) {
+		self.state.lock().unwrap().read_delay = delay;
+	}
+}
+
+impl Drop for MockServer {
+	fn drop(&mut self) {
+		self.shutdown.store(true, Ordering::SeqCst);
+
+		// wake up the accept loop
+		let _ = TcpStream::connect(self.addr);
+
+		if let Some(handle) = self.handle.take() {
+			let _ = handle.join();
+		}
+	}
+}
+
+fn serve_mock<S: Read + Write>(stream: S, state: Arc<Mutex<State>>) {
+	let mut token = String::new();
+	let read = Cell::new(0);
+	let mut answered = false;
+
+	let handler = |request: &Value| {
+		read.set(read.get() + 1);
+
+		let command = request["command"].as_str().unwrap_or_default();
+
+		let mut state = state.lock().unwrap();
+		state.requests.push(request.clone());
+
+		let scripted = state
+			.queued
+			.get_mut(command)
+			.and_then(|queue| queue.pop_front())
+			.or_else(|| state.persistent.get(command).cloned());
+
+		match (scripted, command) {
+			(Some(response), _) => response,
+			(None, "request_token") => {
+				token = new_token();
+				MockResponse::json(json!({ "token": token }))
+			}
+			(None, "auth") => authenticate(&state.users, request, &token),
+			(None, _) => MockResponse::json(json!({ "error": "unknown command" })),
+		}
+	};
+
+	let answering = || {
+		if !answered {
+			answered = true;
+
+			let mut state = state.lock().unwrap();
+			state.read_before_response = state.read_before_response.max(read.get());
+		}
+	};
+
+	serve_batches(stream, handler, answering);
+}
+
+/// Answers the requests arriving on `stream` with the responses returned by
+/// `handler`, until the stream is closed or a [`MockResponse::Close`] is returned.
+/// Requests may be JSON or msgpack encoded, JSON responses are sent back in the
+/// encoding of the request. Requests that arrived together are answered together.
+pub fn serve<S: Read + Write>(stream: S, handler: impl FnMut(&Value) -> MockResponse) {
+	serve_batches(stream, handler, || {});
+}
+
+/// [`serve`], calling `answering` before each batch of responses is written.
+fn serve_batches<S: Read + Write>(
+	stream: S,
+	mut handler: impl FnMut(&Value) -> MockResponse,
+	mut answering: impl FnMut(),
+) {
+	let mut reader = BufReader::new(stream);
+	let mut pending = Vec::new();
+
+	while let Some((request, msgpack)) = read_request(&mut reader) {
+		let close = match handler(&request) {
+			MockResponse::Json(mut value) => {
+				if let Some(object) = value.as_object_mut() {
+					let command = request["command"].clone();
+					object.entry("command").or_insert(command);
+				}
+
+				if msgpack {
+					let payload = rmp_serde::to_vec(&value).unwrap_or_default();
+					pending.extend(Frame::plain(payload).encode());
+				} else {
+					pending.extend(Frame::plain(value.to_string().into_bytes()).encode());
+				}
+				false
+			}
+			MockResponse::Plain(payload) => {
+				pending.extend(Frame::plain(payload).encode());
+				false
+			}
+			MockResponse::Positioned(position, payload) => {
+				pending.extend(Frame::positioned(position, payload).encode());
+				false
+			}
+			MockResponse::Raw(data) => {
+				pending.extend(data);
+				false
+			}
+			MockResponse::Silent => false,
+			MockResponse::Close => true,
+		};
+
+		// the client is still sending, answer the requests it sent so far at once
+		if !close && !reader.buffer().is_empty() {
+			continue;
+		}
+
+		if !pending.is_empty() {
+			answering();
+
+			let writer = reader.get_mut();
+			if writer
+				.write_all(&pending)
+				.and_then(|_| writer.flush())
+				.is_err()
+			{
+				break;
+			}
+
+			pending.clear();
+		}
+
+		if close {
+			break;
+		}
+	}
+}
+
+/// Reads the next request, detecting its encoding from the first byte. Returns
+/// the command and whether it was msgpack encoded.
+fn read_request<S: Read>(reader: &mut BufReader<S>) -> Option<(Value, bool)> {
+	let first = *reader.fill_buf().ok()?.first()?;
+	let msgpack = codec::is_msgpack(&[first]);
+
+	if msgpack {
+		let value: Value = rmp_serde::from_read(&mut *reader).ok()?;
+
+		let mut terminator = [0; 2];
+		reader.read_exact(&mut terminator).ok()?;
+
+		Some((value, true))
+	} else {
+		let mut line = Vec::new();
+		reader.read_until(b'\n', &mut line).ok()?;
+
+		let (request, _) = codec::decode_request(&line)?;
+		Some((
+			serde_json::from_slice(&request).unwrap_or(Value::Null),
+			false,
+		))
+	}
+}
+
+/// Signed token a client sends in `auth` after logging in with `password` to a
+/// `request_token` answered with `token`.
+pub fn signed_token(password: &str, token: &str) -> String {
+	Bone::get_signed_token(password, token)
+}
+
+fn new_token() -> String {
+	let mut buf = [0; 32];
+	openssl::rand::rand_bytes(&mut buf).unwrap_or_default();
+	hex::encode(buf)
+}
+
+fn authenticate(users: &HashMap<String, String>, request: &Value, token: &str) -> MockResponse {
+	let username = request["payload"]["username"].as_str().unwrap_or_default();
+	let signed = request["payload"]["signed_token"]
+		.as_str()
+		.unwrap_or_default();
+
+	match users.get(username) {
+		Some(password) if !token.is_empty() && signed_token(password, token) == signed => {
+			MockResponse::json(json!({ "username": username }))
+		}
+		_ => MockResponse::json(json!({ "error": "wrong username or password" })),
+	}
+}
@@ -0,0 +1,392 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::ssl::{
+	ConnectConfiguration, HandshakeError, SslConnector, SslConnectorBuilder, SslFiletype,
+	SslMethod, SslRef, SslVerifyMode,
+};
+use openssl::x509::{X509Ref, X509VerifyResult, X509};
+
+use crate::known_hosts::{self, KnownHosts};
+use crate::transport::{Connection, Stream};
+use crate::{BoneError, Result};
+
+/// Identity the device certificate has to match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TlsIdentity {
+	/// Check the certificate against the host name or ip address that is connected to.
+	Host,
+	/// Check the certificate against the given host name.
+	Hostname(String),
+	/// Check that the certificate was issued for the given serial number, either as
+	/// common name or as DNS subject alternative name.
+	Serial(u32),
+}
+
+/// Certificate and private key presented to devices that require client authentication.
+#[derive(Clone, Debug)]
+pub enum ClientIdentity {
+	/// PEM encoded certificate (chain) and private key, which may be the same file.
+	Pem { cert: PathBuf, key: PathBuf },
+	/// PKCS#12 archive holding certificate, private key and optional chain.
+	Pkcs12 { path: PathBuf, password: String },
+}
+
+impl ClientIdentity {
+	fn apply(&self, builder: &mut SslConnectorBuilder) -> Result<()> {
+		match self {
+			ClientIdentity::Pem { cert, key } => {
+				builder.set_certificate_chain_file(cert)?;
+				builder.set_private_key_file(key, SslFiletype::PEM)?;
+			}
+			ClientIdentity::Pkcs12 { path, password } => {
+				let archive = Pkcs12::from_der(&fs::read(path)?)?.parse2(password)?;
+
+				if let Some(ref cert) = archive.cert {
+					builder.set_certificate(cert)?;
+				}
+
+				if let Some(ref pkey) = archive.pkey {
+					builder.set_private_key(pkey)?;
+				}
+
+				for cert in archive.ca.into_iter().flatten() {
+					builder.add_extra_chain_cert(cert)?;
+				}
+			}
+		}
+
+		builder.check_private_key()?;
+
+		Ok(())
+	}
+}
+
+/// TLS settings used by [`crate::Bone::connect`] when encryption is enabled.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+	/// Verify the device certificate. Disabling this accepts any certificate.
+	pub verify: bool,
+	/// PEM file with trusted CA certificates, in addition to the system defaults.
+	pub ca_file: Option<PathBuf>,
+	/// Directory with hashed CA certificates, in addition to the system defaults.
+	pub ca_path: Option<PathBuf>,
+	pub identity: TlsIdentity,
+	/// Known hosts file used to trust certificates that fail CA verification on
+	/// first use and to pin them for later connections.
+	pub known_hosts: Option<PathBuf>,
+	/// Add unknown devices to the known hosts file during the handshake. When
+	/// disabled they are still accepted as [`PeerTrust::FirstUse`], but only added
+	/// by [`crate::Bone::pin_peer`], e.g. once it is clear which of several
+	/// connections is kept.
+	pub pin_first_use: bool,
+	/// Client certificate for devices that only accept known clients.
+	pub client_identity: Option<ClientIdentity>,
+}
+
+/// Reason the device certificate was accepted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerTrust {
+	/// Verification is disabled.
+	Unverified,
+	/// The certificate chains up to a trusted CA and matches the identity.
+	CaVerified,
+	/// The certificate matches the fingerprint in the known hosts file.
+	Pinned,
+	/// The device was unknown and its fingerprint has been added to the known hosts file.
+	FirstUse,
+}
+
+impl Default for TlsConfig {
+	fn default() -> Self {
+		TlsConfig {
+			verify: true,
+			ca_file: None,
+			ca_path: None,
+			identity: TlsIdentity::Host,
+			known_hosts: None,
+			pin_first_use: true,
+			client_identity: None,
+		}
+	}
+}
+
+impl TlsConfig {
+	/// Accept any certificate without verification.
+	pub fn insecure() -> Self {
+		TlsConfig {
+			verify: false,
+			..Default::default()
+		}
+	}
+
+	/// Connection settings for a handshake with the device.
+	pub(crate) fn configure(&self) -> Result<ConnectConfiguration> {
+		let mut ssl_config = self.build_connector()?.configure()?;
+		ssl_config.set_verify_hostname(self.verify_hostname());
+
+		Ok(ssl_config)
+	}
+
+	fn build_connector(&self) -> Result<SslConnector> {
+		let mut builder = SslConnector::builder(SslMethod::tls())?;
+
+		if !self.verify {
+			builder.set_verify(SslVerifyMode::NONE);
+		} else if self.known_hosts.is_some() {
+			// let the handshake finish, the result is checked against the known hosts afterwards
+			builder.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+		} else {
+			builder.set_verify(SslVerifyMode::PEER);
+		}
+
+		if self.verify {
+			if let Some(ref ca_file) = self.ca_file {
+				builder.set_ca_file(ca_file)?;
+			}
+
+			if let Some(ref ca_path) = self.ca_path {
+				builder.load_verify_locations(None, Some(ca_path))?;
+			}
+		}
+
+		if let Some(ref client_identity) = self.client_identity {
+			client_identity.apply(&mut builder)?;
+		}
+
+		Ok(builder.build())
+	}
+
+	/// Name handed to openssl for SNI and hostname verification.
+	pub(crate) fn server_name<'a>(&'a self, host: &'a str) -> &'a str {
+		match self.identity {
+			TlsIdentity::Hostname(ref name) => name,
+			_ => {
+				let host = host.trim_start_matches('[').trim_end_matches(']');
+
+				// zone ids of link local addresses are meaningless to the peer
+				host.split('%').next().unwrap_or(host)
+			}
+		}
+	}
+
+	/// Key of the device in the known hosts file.
+	pub(crate) fn known_hosts_name(&self, host: &str, port: &str) -> String {
+		match self.identity {
+			TlsIdentity::Serial(serial) => format!("serial:{}", serial),
+			_ => format!("[{}]:{}", self.server_name(host), port),
+		}
+	}
+
+	fn verify_hostname(&self) -> bool {
+		self.verify && !matches!(self.identity, TlsIdentity::Serial(_))
+	}
+
+	/// Runs the handshake on `stream` and checks the certificate the device presents.
+	pub(crate) fn handshake(
+		&self,
+		stream: Box<dyn Stream>,
+		host: &str,
+		known_hosts_name: &str,
+	) -> Result<Connection> {
+		let stream = ReceiveTracking {
+			inner: stream,
+			received: false,
+		};
+
+		let stream = match self.configure()?.connect(self.server_name(host), stream) {
+			Ok(stream) => stream,
+			// the connection broke before the device sent anything, so it is no TLS error
+			Err(HandshakeError::Failure(stream)) if !stream.get_ref().received => {
+				return Err(closed_before_handshake(stream.into_error()))
+			}
+			Err(e) => return Err(e.into()),
+		};
+
+		let peer_fingerprint = peer_fingerprint(stream.ssl())?;
+		let peer_trust = self.verify_peer(stream.ssl(), known_hosts_name)?;
+
+		Ok(Connection {
+			stream: Box::new(stream),
+			peer_trust: Some(peer_trust),
+			peer_fingerprint,
+		})
+	}
+
+	/// Decides whether the certificate presented in the finished handshake is trusted.
+	pub(crate) fn verify_peer(&self, ssl: &SslRef, name: &str) -> Result<PeerTrust> {
+		self.verify_certificate(ssl.peer_certificate(), ssl.verify_result(), name)
+	}
+
+	/// Decides whether `cert`, for which openssl reported `verify_result`, is
+	/// trusted. May read and write the known hosts file.
+	pub(crate) fn verify_certificate(
+		&self,
+		cert: Option<X509>,
+		verify_result: X509VerifyResult,
+		name: &str,
+	) -> Result<PeerTrust> {
+		if !self.verify {
+			return Ok(PeerTrust::Unverified);
+		}
+
+		let cert = cert.ok_or_else(|| {
+			BoneError::Certificate(String::from("device did not present a certificate"))
+		})?;
+
+		let ca_result = if verify_result == X509VerifyResult::OK {
+			self.check_serial(&cert)
+		} else {
+			Err(BoneError::Certificate(
+				verify_result.error_string().to_string(),
+			))
+		};
+
+		let path = match (ca_result, &self.known_hosts) {
+			(Ok(()), _) => return Ok(PeerTrust::CaVerified),
+			(Err(e), None) => return Err(e),
+			(Err(_), Some(path)) => path,
+		};
+
+		let presented = known_hosts::fingerprint(&cert)?;
+
+		match check_known_host(path, name, &presented)? {
+			Some(known_hosts) if self.pin_first_use => {
+				pin(known_hosts, name, &presented)?;
+				Ok(PeerTrust::FirstUse)
+			}
+			Some(_) => Ok(PeerTrust::FirstUse),
+			None => Ok(PeerTrust::Pinned),
+		}
+	}
+
+	/// Adds the device accepted on first use to the known hosts file, unless it has
+	/// been added with another fingerprint in the meantime.
+	pub(crate) fn pin_peer(&self, name: &str, fingerprint: &str) -> Result<()> {
+		let path = match self.known_hosts {
+			Some(ref path) => path,
+			None => return Ok(()),
+		};
+
+		match check_known_host(path, name, fingerprint)? {
+			Some(known_hosts) => pin(known_hosts, name, fingerprint),
+			None => Ok(()),
+		}
+	}
+
+	/// Checks the serial number identity, which openssl can't verify by itself.
+	fn check_serial(&self, cert: &X509Ref) -> Result<()> {
+		let serial = match self.identity {
+			TlsIdentity::Serial(serial) => serial.to_string(),
+			_ => return Ok(()),
+		};
+
+		let common_names = cert
+			.subject_name()
+			.entries_by_nid(Nid::COMMONNAME)
+			.filter_map(|e| {
+				std::str::from_utf8(e.data().as_slice())
+					.ok()
+					.map(str::to_string)
+			});
+
+		let alt_names = cert
+			.subject_alt_names()
+			.into_iter()
+			.flatten()
+			.filter_map(|n| n.dnsname().map(|s| s.to_string()));
+
+		if common_names.chain(alt_names).any(|name| name == serial) {
+			Ok(())
+		} else {
+			Err(BoneError::Certificate(format!(
+				"certificate was not issued for serial number {}",
+				serial
+			)))
+		}
+	}
+}
+
+/// Stream remembering whether anything has been read from it.
+struct ReceiveTracking {
+	inner: Box<dyn Stream>,
+	received: bool,
+}
+
+impl Read for ReceiveTracking {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let len = self.inner.read(buf)?;
+		self.received |= len > 0;
+		Ok(len)
+	}
+}
+
+impl Write for ReceiveTracking {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.inner.write(buf)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+impl Stream for ReceiveTracking {
+	fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		self.inner.set_read_timeout(timeout)
+	}
+
+	fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		self.inner.set_write_timeout(timeout)
+	}
+
+	fn set_keepalive(&self, time: Option<Duration>) -> std::io::Result<()> {
+		self.inner.set_keepalive(time)
+	}
+}
+
+/// Error of a handshake that failed before the device sent anything, e.g. because
+/// it closed or reset the connection.
+fn closed_before_handshake(error: openssl::ssl::Error) -> BoneError {
+	match error.into_io_error() {
+		Ok(e) => e.into(),
+		// openssl reports the end of the stream without an i/o error
+		Err(_) => BoneError::Io(std::io::Error::new(
+			std::io::ErrorKind::UnexpectedEof,
+			"connection closed before the TLS handshake",
+		)),
+	}
+}
+
+/// Compares `fingerprint` with the one known for `name`. Returns the loaded known
+/// hosts if `name` is unknown, `None` if the fingerprint matches.
+fn check_known_host(path: &Path, name: &str, fingerprint: &str) -> Result<Option<KnownHosts>> {
+	let known_hosts = KnownHosts::load(path)?;
+
+	match known_hosts.get(name) {
+		Some(known) if known == fingerprint => Ok(None),
+		Some(known) => Err(BoneError::FingerprintMismatch {
+			name: name.to_string(),
+			known: known.to_string(),
+			presented: fingerprint.to_string(),
+		}),
+		None => Ok(Some(known_hosts)),
+	}
+}
+
+fn pin(mut known_hosts: KnownHosts, name: &str, fingerprint: &str) -> Result<()> {
+	known_hosts.insert(name, fingerprint);
+	known_hosts.save()
+}
+
+/// SHA-256 fingerprint of the certificate presented in the finished handshake.
+pub(crate) fn peer_fingerprint(ssl: &SslRef) -> Result<Option<String>> {
+	match ssl.peer_certificate() {
+		Some(cert) => Ok(Some(known_hosts::fingerprint(&cert)?)),
+		None => Ok(None),
+	}
+}
@@ -0,0 +1,494 @@
+//! Ways of reaching a device.
+//!
+//! [`crate::Bone`] connects over TCP by default. Other transports are set with
+//! [`crate::Bone::set_transport`], encryption is layered on top of them when enabled.
+
+use std::cell::Cell;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpStream, ToSocketAddrs};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use openssl::ssl::SslStream;
+use socket2::{SockRef, TcpKeepalive};
+
+use crate::{BoneError, PeerTrust, Result};
+
+/// Byte stream to a device.
+pub trait Stream: Read + Write + Send {
+	/// Limits how long a single read may block. Streams without timeouts ignore it.
+	fn set_read_timeout(&self, _timeout: Option<Duration>) -> std::io::Result<()> {
+		Ok(())
+	}
+
+	/// Limits how long a single write may block. Streams without timeouts ignore it.
+	fn set_write_timeout(&self, _timeout: Option<Duration>) -> std::io::Result<()> {
+		Ok(())
+	}
+
+	/// Sends keepalive probes after the connection was idle for `time`. Streams
+	/// without keepalive ignore it.
+	fn set_keepalive(&self, _time: Option<Duration>) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+impl Stream for TcpStream {
+	fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		TcpStream::set_read_timeout(self, timeout)
+	}
+
+	fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		TcpStream::set_write_timeout(self, timeout)
+	}
+
+	fn set_keepalive(&self, time: Option<Duration>) -> std::io::Result<()> {
+		set_tcp_keepalive(SockRef::from(self), time)
+	}
+}
+
+/// Sends keepalive probes on `socket` after it was idle for `time`, or stops sending them.
+pub(crate) fn set_tcp_keepalive(socket: SockRef, time: Option<Duration>) -> std::io::Result<()> {
+	match time {
+		Some(time) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time)),
+		None => socket.set_keepalive(false),
+	}
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+	fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		UnixStream::set_read_timeout(self, timeout)
+	}
+
+	fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		UnixStream::set_write_timeout(self, timeout)
+	}
+}
+
+impl<S: Stream + ?Sized> Stream for Box<S> {
+	fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		(**self).set_read_timeout(timeout)
+	}
+
+	fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		(**self).set_write_timeout(timeout)
+	}
+
+	fn set_keepalive(&self, time: Option<Duration>) -> std::io::Result<()> {
+		(**self).set_keepalive(time)
+	}
+}
+
+impl<S: Stream> Stream for SslStream<S> {
+	fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		self.get_ref().set_read_timeout(timeout)
+	}
+
+	fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		self.get_ref().set_write_timeout(timeout)
+	}
+
+	fn set_keepalive(&self, time: Option<Duration>) -> std::io::Result<()> {
+		self.get_ref().set_keepalive(time)
+	}
+}
+
+/// Timeouts a transport applies to the connections it opens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timeouts {
+	/// Limits how long establishing the connection may take.
+	pub connect: Option<Duration>,
+	pub read: Option<Duration>,
+	pub write: Option<Duration>,
+}
+
+/// An established connection.
+pub struct Connection {
+	pub stream: Box<dyn Stream>,
+	/// How the device certificate was trusted, on encrypted connections.
+	pub peer_trust: Option<PeerTrust>,
+	/// SHA-256 fingerprint of the device certificate, on encrypted connections.
+	pub peer_fingerprint: Option<String>,
+}
+
+impl Connection {
+	/// Unencrypted connection over `stream`.
+	pub fn new(stream: impl Stream + 'static) -> Connection {
+		Connection {
+			stream: Box::new(stream),
+			peer_trust: None,
+			peer_fingerprint: None,
+		}
+	}
+}
+
+/// Opens connections to a device.
+pub trait Transport: Send {
+	/// Opens a new connection, applying the `timeouts` the transport supports.
+	fn connect(&self, timeouts: &Timeouts) -> Result<Connection>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+	fn connect(&self, timeouts: &Timeouts) -> Result<Connection> {
+		(**self).connect(timeouts)
+	}
+}
+
+/// Plain TCP connection, tries all addresses the host resolves to.
+#[derive(Clone, Debug)]
+pub struct TcpTransport {
+	pub host: String,
+	pub port: String,
+}
+
+impl TcpTransport {
+	pub fn new(host: &str, port: &str) -> TcpTransport {
+		TcpTransport {
+			host: host.to_string(),
+			port: port.to_string(),
+		}
+	}
+
+	fn open_socket(&self, connect_timeout: Option<Duration>) -> Result<TcpStream> {
+		let mut last_err = None;
+
+		for addr in resolve(&self.host, &self.port)? {
+			let result = match connect_timeout {
+				Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+				None => TcpStream::connect(addr),
+			};
+
+			match result {
+				Ok(stream) => return Ok(stream),
+				Err(e) => last_err = Some(e),
+			}
+		}
+
+		Err(match last_err {
+			Some(e) => e.into(),
+			None => BoneError::Io(std::io::Error::new(
+				std::io::ErrorKind::NotFound,
+				"could not resolve address",
+			)),
+		})
+	}
+}
+
+impl Transport for TcpTransport {
+	fn connect(&self, timeouts: &Timeouts) -> Result<Connection> {
+		let stream = self.open_socket(timeouts.connect)?;
+
+		// pipelined requests are small, don't hold them back until the previous one is acknowledged
+		stream.set_nodelay(true)?;
+		stream.set_read_timeout(timeouts.read)?;
+		stream.set_write_timeout(timeouts.write)?;
+
+		Ok(Connection::new(stream))
+	}
+}
+
+/// Resolves `host` and `port` to all addresses to try in turn. IPv6 literals
+/// may be enclosed in brackets and carry a zone id, either as interface name or
+/// index, e.g. `fe80::1%eth0`.
+pub fn resolve(host: &str, port: &str) -> Result<Vec<SocketAddr>> {
+	let port = parse_port(port)?;
+
+	match parse_ip_literal(host, port)? {
+		Some(addr) => Ok(vec![addr]),
+		None => Ok((host, port).to_socket_addrs()?.collect()),
+	}
+}
+
+pub(crate) fn parse_port(port: &str) -> Result<u16> {
+	port.parse().map_err(|_| {
+		BoneError::Io(std::io::Error::new(
+			std::io::ErrorKind::InvalidInput,
+			format!("invalid port {:?}", port),
+		))
+	})
+}
+
+/// Parses `host` if it is an ip address, `None` if it is a host name.
+pub(crate) fn parse_ip_literal(host: &str, port: u16) -> Result<Option<SocketAddr>> {
+	let host = host.trim_start_matches('[').trim_end_matches(']');
+
+	let (ip, zone) = match host.split_once('%') {
+		Some((ip, zone)) => (ip, Some(zone)),
+		None => (host, None),
+	};
+
+	let zone = match zone {
+		Some(zone) => zone,
+		None => {
+			return Ok(ip
+				.parse::<IpAddr>()
+				.ok()
+				.map(|ip| SocketAddr::new(ip, port)))
+		}
+	};
+
+	let ip: Ipv6Addr = ip.parse().map_err(|_| {
+		BoneError::Io(std::io::Error::new(
+			std::io::ErrorKind::InvalidInput,
+			format!("zone id on invalid ipv6 address {:?}", host),
+		))
+	})?;
+
+	let scope_id = match zone.parse() {
+		Ok(index) => index,
+		Err(_) => interface_index(zone)?,
+	};
+
+	Ok(Some(SocketAddr::V6(SocketAddrV6::new(
+		ip, port, 0, scope_id,
+	))))
+}
+
+fn interface_index(name: &str) -> Result<u32> {
+	let interfaces = NetworkInterface::show()
+		.map_err(|e| BoneError::Io(std::io::Error::other(e.to_string())))?;
+
+	interfaces
+		.iter()
+		.find(|interface| interface.name == name)
+		.map(|interface| interface.index)
+		.ok_or_else(|| {
+			BoneError::Io(std::io::Error::new(
+				std::io::ErrorKind::NotFound,
+				format!("unknown interface {:?}", name),
+			))
+		})
+}
+
+/// Unix domain socket, e.g. for tools running on the device itself.
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+pub struct UnixTransport {
+	pub path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+	pub fn new(path: impl Into<PathBuf>) -> UnixTransport {
+		UnixTransport { path: path.into() }
+	}
+}
+
+#[cfg(unix)]
+impl Transport for UnixTransport {
+	fn connect(&self, timeouts: &Timeouts) -> Result<Connection> {
+		let stream = UnixStream::connect(&self.path)?;
+
+		stream.set_read_timeout(timeouts.read)?;
+		stream.set_write_timeout(timeouts.write)?;
+
+		Ok(Connection::new(stream))
+	}
+}
+
+/// Runs a command and talks to the device over its stdin and stdout, like the
+/// `ProxyCommand` of ssh, e.g. `ssh -W [fe80::1%eth0]:6451 jumphost`. The
+/// command is run by the shell and killed when the connection is dropped. Its
+/// stderr is inherited. Read and write timeouts apply to the pipes, the connect
+/// timeout is not supported since the command reports no established connection.
+#[derive(Clone, Debug)]
+pub struct CommandTransport {
+	pub command: String,
+}
+
+impl CommandTransport {
+	pub fn new(command: &str) -> CommandTransport {
+		CommandTransport {
+			command: command.to_string(),
+		}
+	}
+
+	fn shell_command(&self) -> Command {
+		let mut command = if cfg!(windows) {
+			let mut command = Command::new("cmd");
+			command.arg("/C");
+			command
+		} else {
+			let mut command = Command::new("sh");
+			command.arg("-c");
+			command
+		};
+
+		command.arg(&self.command);
+		command
+	}
+}
+
+impl Transport for CommandTransport {
+	fn connect(&self, timeouts: &Timeouts) -> Result<Connection> {
+		let mut child = self
+			.shell_command()
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.spawn()?;
+
+		let (stdin, stdout) = match (child.stdin.take(), child.stdout.take()) {
+			(Some(stdin), Some(stdout)) => (stdin, stdout),
+			_ => {
+				let _ = child.kill();
+				return Err(BoneError::Io(std::io::Error::new(
+					std::io::ErrorKind::BrokenPipe,
+					"could not open pipes of command",
+				)));
+			}
+		};
+
+		Ok(Connection::new(CommandStream::new(
+			child, stdin, stdout, timeouts,
+		)))
+	}
+}
+
+/// Pipes of a command. Pipes have no timeouts, so they are read and written on
+/// threads of their own, which are waited for no longer than the timeouts.
+struct CommandStream {
+	child: Child,
+	reads: Receiver<std::io::Result<Vec<u8>>>,
+	writes: Sender<Vec<u8>>,
+	written: Receiver<std::io::Result<()>>,
+	// read from stdout, but not yet returned by `read`
+	pending: Vec<u8>,
+	read_timeout: Cell<Option<Duration>>,
+	write_timeout: Cell<Option<Duration>>,
+}
+
+impl CommandStream {
+	fn new(
+		child: Child,
+		mut stdin: ChildStdin,
+		mut stdout: ChildStdout,
+		timeouts: &Timeouts,
+	) -> CommandStream {
+		let (read_sender, reads) = mpsc::channel();
+		let (writes, write_receiver) = mpsc::channel::<Vec<u8>>();
+		let (written_sender, written) = mpsc::channel();
+
+		// both threads end when the command exits or the stream is dropped
+		thread::spawn(move || {
+			let mut buf = [0; 8192];
+
+			loop {
+				let result = match stdout.read(&mut buf) {
+					Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+					result => result.map(|len| buf[..len].to_vec()),
+				};
+				let end = !matches!(result, Ok(ref data) if !data.is_empty());
+
+				if read_sender.send(result).is_err() || end {
+					break;
+				}
+			}
+		});
+
+		thread::spawn(move || {
+			for data in write_receiver {
+				let result = stdin.write_all(&data).and_then(|_| stdin.flush());
+
+				if written_sender.send(result).is_err() {
+					break;
+				}
+			}
+		});
+
+		CommandStream {
+			child,
+			reads,
+			writes,
+			written,
+			pending: Vec::new(),
+			read_timeout: Cell::new(timeouts.read),
+			write_timeout: Cell::new(timeouts.write),
+		}
+	}
+}
+
+/// Waits for the next message of `receiver`, at most `timeout`.
+fn receive<T>(
+	receiver: &Receiver<T>,
+	timeout: Option<Duration>,
+) -> std::result::Result<T, RecvTimeoutError> {
+	match timeout {
+		Some(timeout) => receiver.recv_timeout(timeout),
+		None => Ok(receiver.recv()?),
+	}
+}
+
+impl Read for CommandStream {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		if self.pending.is_empty() {
+			match receive(&self.reads, self.read_timeout.get()) {
+				Ok(data) => self.pending = data?,
+				Err(RecvTimeoutError::Timeout) => {
+					return Err(std::io::Error::new(
+						std::io::ErrorKind::TimedOut,
+						"command did not answer in time",
+					))
+				}
+				// the command closed its stdout
+				Err(RecvTimeoutError::Disconnected) => return Ok(0),
+			}
+		}
+
+		let len = buf.len().min(self.pending.len());
+		buf[..len].copy_from_slice(&self.pending[..len]);
+		self.pending.drain(..len);
+
+		Ok(len)
+	}
+}
+
+impl Write for CommandStream {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let broken_pipe = || std::io::Error::new(std::io::ErrorKind::BrokenPipe, "command exited");
+
+		self.writes.send(buf.to_vec()).map_err(|_| broken_pipe())?;
+
+		// after a timeout the stream is dropped, so answers can't get out of step
+		match receive(&self.written, self.write_timeout.get()) {
+			Ok(result) => result.map(|_| buf.len()),
+			Err(RecvTimeoutError::Timeout) => Err(std::io::Error::new(
+				std::io::ErrorKind::TimedOut,
+				"command did not take the request in time",
+			)),
+			Err(RecvTimeoutError::Disconnected) => Err(broken_pipe()),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		// every write is flushed before it returns
+		Ok(())
+	}
+}
+
+impl Stream for CommandStream {
+	fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		self.read_timeout.set(timeout);
+		Ok(())
+	}
+
+	fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		self.write_timeout.set(timeout);
+		Ok(())
+	}
+}
+
+impl Drop for CommandStream {
+	fn drop(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+	}
+}
@@ -0,0 +1,142 @@
+#![cfg(feature = "async")]
+
+use std::time::Duration;
+
+use bone_api::testing::{MockResponse, MockServer};
+use bone_api::{AsyncBone, BoneError};
+use serde_json::json;
+
+mod common;
+
+use common::connect_async;
+
+#[tokio::test]
+async fn send_command() {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"date",
+		MockResponse::json(json!({"date": "2021-05-04 21:08:33"})),
+	);
+
+	for msgpack in [false, true] {
+		let mut bone = connect_async(&server, msgpack).await;
+		let response = bone
+			.send_command(&json!({"command": "date"}))
+			.await
+			.unwrap();
+
+		assert_eq!(response["command"], "date");
+		assert_eq!(response["payload"]["date"], "2021-05-04 21:08:33");
+	}
+}
+
+#[tokio::test]
+async fn login() {
+	let server = MockServer::start().unwrap();
+	server.add_user("admin", "secret");
+
+	let mut bone = connect_async(&server, false).await;
+
+	assert!(matches!(
+		bone.login("admin", "wrong").await,
+		Err(BoneError::Auth(_))
+	));
+	assert_eq!(bone.login("admin", "secret").await.unwrap(), "admin");
+}
+
+#[tokio::test]
+async fn raw_commands() {
+	let server = MockServer::start().unwrap();
+	let samples: Vec<u8> = [1.5f32, -2.0]
+		.iter()
+		.flat_map(|v| v.to_be_bytes())
+		.collect();
+	server.respond("sync", MockResponse::Positioned(42, samples));
+	server.respond("dv_data", MockResponse::Plain(b"800fff".to_vec()));
+
+	let mut bone = connect_async(&server, false).await;
+
+	let (position, data) = bone
+		.send_sync_command(&json!({"command": "sync", "payload": {"filter": ["coe"]}}))
+		.await
+		.unwrap();
+	assert_eq!(position, 42);
+	assert_eq!(data, vec![("coe".to_string(), vec![1.5, -2.0])]);
+
+	let dv = bone
+		.send_dv_command(&json!({"command": "dv_data"}))
+		.await
+		.unwrap();
+	assert_eq!(dv, vec![0., (4095. - 2048.) / 4096. * 5.]);
+}
+
+#[tokio::test]
+async fn read_timeout() {
+	let server = MockServer::start().unwrap();
+	server.respond("date", MockResponse::Silent);
+
+	let mut bone = connect_async(&server, false).await;
+	bone.set_read_timeout(Some(Duration::from_millis(100)));
+
+	assert!(matches!(
+		bone.send_command(&json!({"command": "date"})).await,
+		Err(BoneError::Timeout)
+	));
+	assert!(!bone.is_connected());
+}
+
+#[tokio::test]
+async fn typed_commands() {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"date",
+		MockResponse::json(json!({"date": "2021-05-04 21:08:33"})),
+	);
+	server.respond(
+		"channel_data",
+		MockResponse::json(json!({"data": [{"name": "speed", "value": 3000}]})),
+	);
+
+	let mut bone = AsyncBone::new(&server.ip(), &server.port().to_string(), false, false);
+	bone.set_keepalive(Some(Duration::from_secs(30)));
+	bone.connect().await.unwrap();
+
+	assert_eq!(bone.date().await.unwrap().date, "2021-05-04 21:08:33");
+
+	let channels = bone.all_channel_data().await.unwrap();
+	assert_eq!(channels.len(), 1);
+	assert_eq!(channels[0].name, "speed");
+}
+
+#[tokio::test]
+async fn cancelled_request() {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"date",
+		MockResponse::json(json!({"date": "2021-05-04 21:08:33"})),
+	);
+	server.respond("info", MockResponse::json(json!({"serial": 1234})));
+	server.set_read_delay(Some(Duration::from_millis(300)));
+
+	let mut bone = connect_async(&server, false).await;
+
+	// dropped while the device has not answered yet
+	let date = json!({"command": "date"});
+	let pending = tokio::time::timeout(Duration::from_millis(50), bone.send_command(&date));
+	assert!(pending.await.is_err());
+	assert!(!bone.is_connected());
+	assert!(matches!(
+		bone.send_command(&json!({"command": "info"})).await,
+		Err(BoneError::NotConnected)
+	));
+
+	server.set_read_delay(None);
+	bone.connect().await.unwrap();
+
+	let response = bone
+		.send_command(&json!({"command": "info"}))
+		.await
+		.unwrap();
+	assert_eq!(response["command"], "info");
+	assert_eq!(response["payload"]["serial"], 1234);
+}
@@ -0,0 +1,535 @@
+use std::time::Duration;
+
+use bone_api::codec::FramingError;
+use bone_api::testing::{self, MockResponse, MockServer};
+use bone_api::{Bone, BoneError, BoneHandle};
+use serde_json::json;
+
+mod common;
+
+use common::connect;
+
+#[test]
+fn send_command_json() {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"date",
+		MockResponse::json(json!({"date": "2021-05-04 21:08:33"})),
+	);
+
+	let mut bone = connect(&server, false);
+	let response = bone.send_command(&json!({"command": "date"})).unwrap();
+
+	assert_eq!(response["command"], "date");
+	assert_eq!(response["payload"]["date"], "2021-05-04 21:08:33");
+	assert_eq!(server.requests(), vec![json!({"command": "date"})]);
+}
+
+#[test]
+fn send_command_msgpack() {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"serial_number",
+		MockResponse::json(json!({"serial_number": "1234"})),
+	);
+
+	let mut bone = connect(&server, true);
+	let response = bone
+		.send_command(&json!({"command": "serial_number"}))
+		.unwrap();
+
+	assert_eq!(response["payload"]["serial_number"], "1234");
+}
+
+#[test]
+fn numbers_keep_their_type() {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"channel_data",
+		MockResponse::json(json!({"name": "speed", "value": 2.0, "count": 2})),
+	);
+
+	for msgpack in [false, true] {
+		let mut bone = connect(&server, msgpack);
+		let response = bone
+			.send_command(&json!({"command": "channel_data", "payload": {"name": "speed"}}))
+			.unwrap();
+
+		assert!(response["payload"]["value"].is_f64());
+		assert!(response["payload"]["count"].is_u64());
+	}
+}
+
+#[test]
+fn login() {
+	let server = MockServer::start().unwrap();
+	server.add_user("admin", "secret");
+
+	let mut bone = connect(&server, false);
+
+	assert!(matches!(
+		bone.login("admin", "wrong"),
+		Err(BoneError::Auth(_))
+	));
+	assert_eq!(bone.login("admin", "secret").unwrap(), "admin");
+}
+
+#[test]
+fn sync_command() {
+	let server = MockServer::start().unwrap();
+	let payload: Vec<u8> = [1.5f32, -2.0]
+		.iter()
+		.flat_map(|v| v.to_be_bytes())
+		.collect();
+	server.respond("sync", MockResponse::Positioned(42, payload));
+
+	let mut bone = connect(&server, false);
+	let (position, data) = bone
+		.send_sync_command(&json!({"command": "sync", "payload": {"filter": ["coe"]}}))
+		.unwrap();
+
+	assert_eq!(position, 42);
+	assert_eq!(data, vec![("coe".to_string(), vec![1.5, -2.0])]);
+}
+
+#[test]
+fn oversized_frame_disconnects() {
+	let server = MockServer::start().unwrap();
+	server.respond("date", MockResponse::Raw(b"ffffffff".to_vec()));
+
+	let mut bone = connect(&server, false);
+	bone.set_max_frame_size(1024);
+
+	assert!(matches!(
+		bone.send_command(&json!({"command": "date"})),
+		Err(BoneError::Framing(FramingError::TooLarge { .. }))
+	));
+	assert!(!bone.is_connected());
+	assert!(matches!(
+		bone.send_command(&json!({"command": "date"})),
+		Err(BoneError::NotConnected)
+	));
+}
+
+#[test]
+fn invalid_header() {
+	let server = MockServer::start().unwrap();
+	server.respond("date", MockResponse::Raw(b"+0000001x".to_vec()));
+
+	let mut bone = connect(&server, false);
+
+	assert!(matches!(
+		bone.send_command(&json!({"command": "date"})),
+		Err(BoneError::Framing(FramingError::InvalidHeader(_)))
+	));
+}
+
+#[test]
+fn read_timeout() {
+	let server = MockServer::start().unwrap();
+	server.respond("date", MockResponse::Silent);
+
+	let mut bone = connect(&server, false);
+	bone.set_read_timeout(Some(Duration::from_millis(100)))
+		.unwrap();
+
+	assert!(matches!(
+		bone.send_command(&json!({"command": "date"})),
+		Err(BoneError::Timeout)
+	));
+}
+
+#[test]
+fn not_connected() {
+	let mut bone = Bone::new("127.0.0.1", "6450", false, false);
+
+	assert!(matches!(
+		bone.send_command(&json!({"command": "date"})),
+		Err(BoneError::NotConnected)
+	));
+}
+
+#[test]
+fn send_batch() {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"serial_number",
+		MockResponse::json(json!({"serial_number": "1234"})),
+	);
+	server.respond(
+		"date",
+		MockResponse::json(json!({"date": "2021-05-04 21:08:33"})),
+	);
+	server.respond_once("board_temp", MockResponse::Close);
+
+	let mut bone = connect(&server, true);
+	bone.set_pipeline_depth(2);
+
+	let commands: Vec<_> = ["serial_number", "date", "unknown", "serial_number", "date"]
+		.iter()
+		.map(|command| json!({ "command": command }))
+		.collect();
+	let results = bone.send_batch(&commands);
+
+	assert_eq!(results.len(), 5);
+	for (command, result) in commands.iter().zip(&results) {
+		assert_eq!(result.as_ref().unwrap()["command"], command["command"]);
+	}
+	assert_eq!(
+		results[1].as_ref().unwrap()["payload"]["date"],
+		"2021-05-04 21:08:33"
+	);
+	assert_eq!(
+		results[2].as_ref().unwrap()["payload"]["error"],
+		"unknown command"
+	);
+	assert_eq!(server.requests(), commands);
+
+	// raw data commands are rejected without being sent
+	let results = bone.send_batch(&[json!({"command": "sync"}), json!({"command": "date"})]);
+
+	assert!(matches!(results[0], Err(BoneError::Decode(_))));
+	assert_eq!(
+		results[1].as_ref().unwrap()["payload"]["date"],
+		"2021-05-04 21:08:33"
+	);
+	assert_eq!(server.requests().len(), commands.len() + 1);
+
+	// the commands after a broken connection fail
+	bone.set_pipeline_depth(1);
+	let results = bone.send_batch(&[json!({"command": "board_temp"}), json!({"command": "date"})]);
+
+	assert!(matches!(results[0], Err(BoneError::Io(_))));
+	assert!(matches!(results[1], Err(BoneError::NotConnected)));
+	assert!(!bone.is_connected());
+}
+
+#[test]
+fn shared_handle() {
+	fn assert_send<T: Send>() {}
+	assert_send::<Bone>();
+
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"serial_number",
+		MockResponse::json(json!({"serial_number": "1234"})),
+	);
+
+	let handle = BoneHandle::new(connect(&server, false));
+
+	let workers: Vec<_> = (0..4)
+		.map(|_| {
+			let handle = handle.clone();
+			std::thread::spawn(move || {
+				for _ in 0..10 {
+					let response = handle
+						.send_command(&json!({"command": "serial_number"}))
+						.unwrap();
+					assert_eq!(response["payload"]["serial_number"], "1234");
+				}
+			})
+		})
+		.collect();
+
+	for worker in workers {
+		worker.join().unwrap();
+	}
+
+	assert_eq!(server.requests().len(), 40);
+}
+
+#[test]
+fn send_batch_pipelines() {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"date",
+		MockResponse::json(json!({"date": "2021-05-04 21:08:33"})),
+	);
+	server.set_read_delay(Some(Duration::from_millis(100)));
+
+	// one command at a time
+	let mut bone = connect(&server, false);
+	for _ in 0..2 {
+		bone.send_command(&json!({"command": "date"})).unwrap();
+	}
+	assert_eq!(server.requests_before_first_response(), 1);
+
+	let handle = BoneHandle::new(connect(&server, true));
+	let results = handle.send_batch_msgpack(&vec![json!({"command": "date"}); 4]);
+
+	assert_eq!(results.len(), 4);
+	for result in results {
+		assert_eq!(
+			result.unwrap()["payload"]["date"].as_str(),
+			Some("2021-05-04 21:08:33")
+		);
+	}
+	assert!(server.requests_before_first_response() > 1);
+}
+
+#[test]
+fn handle_recovers_from_panic() {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"serial_number",
+		MockResponse::json(json!({"serial_number": "1234"})),
+	);
+
+	let handle = BoneHandle::new(connect(&server, false));
+
+	let worker = handle.clone();
+	let panicked = std::thread::spawn(move || {
+		let _bone = worker.lock();
+		panic!("panic while holding the lock");
+	})
+	.join();
+	assert!(panicked.is_err());
+
+	// the interrupted connection is dropped once, later calls use a new one
+	assert!(!handle.is_connected());
+	handle.connect().unwrap();
+
+	for _ in 0..2 {
+		let response = handle
+			.send_command(&json!({"command": "serial_number"}))
+			.unwrap();
+		assert_eq!(response["payload"]["serial_number"], "1234");
+	}
+
+	assert!(handle.is_connected());
+	assert_eq!(server.connections(), 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_transport() {
+	use bone_api::transport::UnixTransport;
+	use std::os::unix::net::UnixListener;
+
+	let path = std::env::temp_dir().join(format!("bone_test_{}.sock", std::process::id()));
+	let _ = std::fs::remove_file(&path);
+	let listener = UnixListener::bind(&path).unwrap();
+
+	std::thread::spawn(move || {
+		let (stream, _) = listener.accept().unwrap();
+		testing::serve(stream, |_| {
+			MockResponse::json(json!({"serial_number": "1234"}))
+		});
+	});
+
+	let mut bone = Bone::new("localhost", "6450", false, false);
+	bone.set_transport(UnixTransport::new(&path));
+	bone.connect().unwrap();
+
+	let response = bone
+		.send_command(&json!({"command": "serial_number"}))
+		.unwrap();
+	assert_eq!(response["payload"]["serial_number"], "1234");
+
+	let _ = std::fs::remove_file(&path);
+}
+
+#[cfg(unix)]
+#[test]
+fn command_transport() {
+	use bone_api::transport::CommandTransport;
+
+	let mut bone = Bone::new("localhost", "6450", false, false);
+	bone.set_transport(CommandTransport::new(
+		r#"printf '0000000d{"payload":1}'; cat > /dev/null"#,
+	));
+	bone.connect().unwrap();
+
+	let response = bone.send_command(&json!({"command": "date"})).unwrap();
+	assert_eq!(response["payload"], 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn command_transport_timeouts() {
+	use bone_api::transport::CommandTransport;
+
+	let mut bone = Bone::new("localhost", "6450", false, false);
+	bone.set_transport(CommandTransport::new("cat > /dev/null"));
+	bone.set_read_timeout(Some(Duration::from_millis(100)))
+		.unwrap();
+	bone.connect().unwrap();
+
+	assert!(matches!(
+		bone.send_command(&json!({"command": "date"})),
+		Err(BoneError::Timeout)
+	));
+	assert!(!bone.is_connected());
+
+	// the command exits without answering
+	bone.set_transport(CommandTransport::new("exit 0"));
+	bone.connect().unwrap();
+
+	assert!(matches!(
+		bone.send_command(&json!({"command": "date"})),
+		Err(BoneError::Io(_))
+	));
+}
+
+#[test]
+fn resolve_ipv6_literals() {
+	use bone_api::transport::resolve;
+	use std::net::SocketAddr;
+
+	assert_eq!(
+		resolve("[::1]", "6451").unwrap(),
+		vec!["[::1]:6451".parse::<SocketAddr>().unwrap()]
+	);
+	assert_eq!(
+		resolve("fe80::b5:b1ff:fe00:1%3", "6451").unwrap(),
+		vec!["[fe80::b5:b1ff:fe00:1%3]:6451"
+			.parse::<SocketAddr>()
+			.unwrap()]
+	);
+	assert!(resolve("127.0.0.1%3", "6451").is_err());
+	assert!(resolve("::1", "port").is_err());
+}
+
+#[test]
+fn connect_tries_all_addresses() {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"serial_number",
+		MockResponse::json(json!({"serial_number": "1234"})),
+	);
+
+	// localhost may resolve to ::1 first, where nothing listens
+	let mut bone = Bone::new("localhost", &server.port().to_string(), false, false);
+	bone.connect().unwrap();
+
+	let response = bone
+		.send_command(&json!({"command": "serial_number"}))
+		.unwrap();
+	assert_eq!(response["payload"]["serial_number"], "1234");
+}
+
+#[test]
+fn reconnect_replays_login() {
+	use bone_api::{ReconnectEvent, ReconnectPolicy};
+	use std::sync::{Arc, Mutex};
+
+	let server = MockServer::start().unwrap();
+	server.add_user("admin", "secret");
+	server.respond(
+		"date",
+		MockResponse::json(json!({"date": "2021-05-04 21:08:33"})),
+	);
+	server.respond_once("date", MockResponse::Close);
+	server.respond_once("set_alias", MockResponse::Close);
+
+	let events = Arc::new(Mutex::new(Vec::new()));
+	let mut bone = connect(&server, false);
+	bone.set_reconnect_policy(Some(ReconnectPolicy {
+		initial_backoff: Duration::from_millis(10),
+		..Default::default()
+	}));
+	bone.set_reconnect_handler({
+		let events = events.clone();
+		move |event| {
+			events.lock().unwrap().push(match event {
+				ReconnectEvent::Lost(_) => String::from("lost"),
+				ReconnectEvent::Reconnected { .. } => String::from("reconnected"),
+				ReconnectEvent::Reauthenticated { username } => username.to_string(),
+				other => format!("{:?}", other),
+			})
+		}
+	});
+	bone.login("admin", "secret").unwrap();
+
+	// idempotent commands are sent again on the new connection
+	let response = bone.send_command(&json!({"command": "date"})).unwrap();
+	assert_eq!(response["payload"]["date"], "2021-05-04 21:08:33");
+
+	// others fail, but leave the client connected
+	assert!(matches!(
+		bone.send_command(&json!({"command": "set_alias", "payload": {"alias": "x"}})),
+		Err(BoneError::Io(_))
+	));
+	assert!(bone.is_connected());
+
+	let commands: Vec<_> = server
+		.requests()
+		.iter()
+		.map(|request| request["command"].as_str().unwrap().to_string())
+		.collect();
+	assert_eq!(
+		commands,
+		[
+			"request_token",
+			"auth",
+			"date",
+			"request_token",
+			"auth",
+			"date",
+			"set_alias",
+			"request_token",
+			"auth",
+		]
+	);
+	assert_eq!(
+		*events.lock().unwrap(),
+		[
+			"lost",
+			"reconnected",
+			"admin",
+			"lost",
+			"reconnected",
+			"admin"
+		]
+	);
+}
+
+#[test]
+fn keepalive() {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"serial_number",
+		MockResponse::json(json!({"serial_number": "1234"})),
+	);
+
+	let mut bone = Bone::new(&server.ip(), &server.port().to_string(), false, false);
+	bone.set_keepalive(Some(Duration::from_secs(30))).unwrap();
+	bone.connect().unwrap();
+
+	bone.send_command(&json!({"command": "serial_number"}))
+		.unwrap();
+	bone.set_keepalive(None).unwrap();
+}
+
+#[test]
+fn session_expired() {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"add_user",
+		MockResponse::json(json!({"error": "Not authenticated"})),
+	);
+
+	let mut bone = connect(&server, false);
+
+	match bone.send_command(&json!({"command": "add_user"})) {
+		Err(BoneError::SessionExpired(message)) => assert_eq!(message, "Not authenticated"),
+		other => panic!("unexpected result {:?}", other),
+	}
+
+	// other errors are left to the caller
+	let response = bone.send_command(&json!({"command": "unknown"})).unwrap();
+	assert_eq!(response["payload"]["error"], "unknown command");
+
+	for error in [
+		"user unauthorized to write channel",
+		"Not authenticated as admin",
+		"session expired for channel",
+	] {
+		server.respond_once("set_channel", MockResponse::json(json!({ "error": error })));
+
+		let response = bone
+			.send_command(&json!({"command": "set_channel"}))
+			.unwrap();
+		assert_eq!(response["payload"]["error"], error);
+	}
+}
@@ -0,0 +1,28 @@
+//! Fixtures shared by the integration tests.
+
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use bone_api::testing::MockServer;
+#[cfg(feature = "async")]
+use bone_api::AsyncBone;
+use bone_api::Bone;
+
+/// Client connected to `server`, with a read timeout so a broken mock fails the
+/// test instead of hanging it.
+pub fn connect(server: &MockServer, msgpack: bool) -> Bone {
+	let mut bone = Bone::new(&server.ip(), &server.port().to_string(), msgpack, false);
+	bone.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	bone.connect().unwrap();
+	bone
+}
+
+/// Async counterpart of [`connect`].
+#[cfg(feature = "async")]
+pub async fn connect_async(server: &MockServer, msgpack: bool) -> AsyncBone {
+	let mut bone = AsyncBone::new(&server.ip(), &server.port().to_string(), msgpack, false);
+	bone.set_read_timeout(Some(Duration::from_secs(5)));
+	bone.connect().await.unwrap();
+	bone
+}
@@ -0,0 +1,63 @@
+use bone_api::discovery::{
+	is_device_address, link_local_address, serial_from_address, serial_from_host,
+};
+
+#[test]
+fn device_addresses() {
+	let address = link_local_address(0x1234);
+
+	assert_eq!(address.to_string(), "fe80::b5:b1ff:fe12:34");
+	assert!(is_device_address(&address));
+	assert!(!is_device_address(&"fe80::1".parse().unwrap()));
+	assert!(!is_device_address(
+		&"2001:db8::b5:b1ff:fe12:34".parse().unwrap()
+	));
+}
+
+#[test]
+fn serial_from_addresses() {
+	for serial in [0, 7, 0x1234, 0xffff, 0x12345, 0x123456] {
+		assert_eq!(
+			serial_from_address(&link_local_address(serial)),
+			Some(serial)
+		);
+	}
+
+	assert_eq!(serial_from_host("[fe80::b5:b1ff:fe00:2a%eth0]"), Some(42));
+	assert_eq!(serial_from_host("fe80::b5:b1ff:fe00:2a"), Some(42));
+	assert_eq!(serial_from_host("fe80::1%eth0"), None);
+	assert_eq!(serial_from_host("localhost"), None);
+	// the address of 0x120ab is shared with 0x12ab
+	assert_eq!(serial_from_host("fe80::b5:b1ff:fe12:ab"), Some(0x12ab));
+}
+
+#[test]
+fn connect_serial_uses_first_answering_interface() {
+	use bone_api::discovery::connect_serial;
+	use bone_api::BoneError;
+
+	let interfaces: Vec<String> = ["eth0", "eth1", "docker0"]
+		.iter()
+		.map(|s| s.to_string())
+		.collect();
+
+	let (host, interface) = connect_serial(42, &interfaces, |host| {
+		if host.ends_with("%eth1") {
+			Ok(host.to_string())
+		} else {
+			Err(BoneError::Timeout)
+		}
+	})
+	.unwrap();
+	assert_eq!(host, "fe80::b5:b1ff:fe00:2a%eth1");
+	assert_eq!(interface, "eth1");
+
+	let result = connect_serial(42, &interfaces, |host| -> bone_api::Result<()> {
+		if host.ends_with("%docker0") {
+			Err(BoneError::Certificate(String::from("wrong serial")))
+		} else {
+			Err(BoneError::Timeout)
+		}
+	});
+	assert!(matches!(result, Err(BoneError::Certificate(_))));
+}
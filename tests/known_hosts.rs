@@ -0,0 +1,39 @@
+use std::fs;
+use std::path::PathBuf;
+
+use bone_api::known_hosts::KnownHosts;
+
+fn temp_path(name: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!("bone_api-{}-{}", name, std::process::id()));
+	let _ = fs::remove_dir_all(&dir);
+	fs::create_dir_all(&dir).unwrap();
+	dir.join("known_hosts")
+}
+
+#[test]
+fn save_keeps_comments() {
+	let path = temp_path("save_keeps_comments");
+	fs::write(
+		&path,
+		"# devices in the lab\nserial:7 sha256:aa\n\n# old one\nserial:8 sha256:bb\nhost:10.0.0.1 sha256:cc\n",
+	)
+	.unwrap();
+
+	let mut known_hosts = KnownHosts::load(&path).unwrap();
+	assert_eq!(known_hosts.get("serial:8"), Some("sha256:bb"));
+
+	known_hosts.insert("serial:7", "sha256:dd");
+	assert!(known_hosts.remove("serial:8"));
+	known_hosts.insert("serial:9", "sha256:ee");
+	known_hosts.save().unwrap();
+
+	assert_eq!(
+		fs::read_to_string(&path).unwrap(),
+		"# devices in the lab\nserial:7 sha256:dd\n\n# old one\nhost:10.0.0.1 sha256:cc\nserial:9 sha256:ee\n"
+	);
+
+	// no temporary files are left behind
+	assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+
+	fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
@@ -0,0 +1,139 @@
+use bone_api::models::{
+	ChannelAttributes, ChannelAttributesRequest, ChannelList, ChannelRequest, ChannelValue, Request,
+};
+use bone_api::testing::{MockResponse, MockServer};
+use bone_api::BoneError;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+mod common;
+
+use common::connect;
+
+#[test]
+fn typed_commands() {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"date",
+		MockResponse::json(json!({
+			"date": "2021-05-04 21:08:33",
+			"timesync": true,
+			"timezone": "Europe/Berlin",
+			"timezone offset": "+0200"
+		})),
+	);
+	server.respond(
+		"serial_number",
+		MockResponse::json(json!({"serial_number": 1234})),
+	);
+	server.respond(
+		"channel_data",
+		MockResponse::json(json!({"data": [
+			{"name": "temp", "value": 21.5, "unit": "°C"},
+			{"name": "speed", "value": 3000}
+		]})),
+	);
+	server.respond("remove_user", MockResponse::json(json!({})));
+
+	let mut bone = connect(&server, false);
+
+	let date = bone.date().unwrap();
+	assert_eq!(date.date, "2021-05-04 21:08:33");
+	assert_eq!(date.timezone_offset.as_deref(), Some("+0200"));
+	assert_eq!(date.timeserver, None);
+
+	assert_eq!(bone.serial_number().unwrap().serial_number, "1234");
+
+	assert_eq!(
+		bone.all_channel_data().unwrap(),
+		vec![
+			ChannelValue {
+				name: "temp".to_string(),
+				value: 21.5,
+				unit: Some("°C".to_string()),
+			},
+			ChannelValue {
+				name: "speed".to_string(),
+				value: 3000.,
+				unit: None,
+			},
+		]
+	);
+
+	bone.remove_user("guest").unwrap();
+
+	assert_eq!(
+		server.requests(),
+		vec![
+			json!({"command": "date"}),
+			json!({"command": "serial_number"}),
+			json!({"command": "channel_data", "payload": {"all": true}}),
+			json!({"command": "remove_user", "payload": {"username": "guest"}}),
+		]
+	);
+}
+
+#[test]
+fn device_errors() {
+	let server = MockServer::start().unwrap();
+	let mut bone = connect(&server, false);
+
+	match bone.channel_data("missing") {
+		Err(BoneError::Device { message }) => assert_eq!(message, "unknown command"),
+		other => panic!("unexpected result {:?}", other),
+	}
+
+	// a response that doesn't fit the model
+	server.respond(
+		"cycle_time",
+		MockResponse::json(json!({"cycle_time": "fast"})),
+	);
+	assert!(matches!(bone.cycle_time(), Err(BoneError::Json(_))));
+}
+
+#[test]
+fn call_other_commands() {
+	#[derive(Serialize)]
+	struct SetAlias {
+		alias: String,
+	}
+
+	impl Request for SetAlias {
+		const COMMAND: &'static str = "set_alias";
+	}
+
+	#[derive(Debug, Deserialize, PartialEq)]
+	struct Alias {
+		alias: String,
+	}
+
+	let server = MockServer::start().unwrap();
+	server.respond("set_alias", MockResponse::json(json!({"alias": "bench"})));
+	server.respond(
+		"channel_attributes",
+		MockResponse::json(json!({"data": [{"name": "temp", "unit": "°C", "min": -40}]})),
+	);
+
+	let mut bone = connect(&server, false);
+
+	let response: Alias = bone
+		.call(&SetAlias {
+			alias: "bench".to_string(),
+		})
+		.unwrap();
+	assert_eq!(response.alias, "bench");
+
+	let request = ChannelRequest {
+		filter: Some(vec!["temp".to_string()]),
+		hidden: Some(true),
+		..ChannelRequest::all()
+	};
+	let list: ChannelList<ChannelAttributes> =
+		bone.call(&ChannelAttributesRequest(request)).unwrap();
+	assert_eq!(list.data[0].other["min"], -40);
+
+	assert_eq!(
+		server.requests()[1],
+		json!({"command": "channel_attributes", "payload": {"all": true, "hidden": true, "filter": ["temp"]}})
+	);
+}
@@ -0,0 +1,110 @@
+use bone_api::codec;
+use bone_api::msgpack::{self, BinaryFormat, Value};
+use bone_api::testing::{MockResponse, MockServer};
+use serde_json::json;
+
+mod common;
+
+use common::connect;
+
+fn encode(value: &Value) -> Vec<u8> {
+	let mut buffer = Vec::new();
+	rmpv::encode::write_value(&mut buffer, value).unwrap();
+	buffer
+}
+
+#[test]
+fn lossless_response() {
+	let payload = Value::Map(vec![
+		(Value::from("blob"), Value::Binary(vec![0, 1, 2, 255])),
+		(Value::from("ext"), Value::Ext(5, vec![0xca, 0xfe])),
+		(Value::from("counter"), Value::from(u64::MAX)),
+		(Value::from("value"), Value::from(f64::NAN)),
+		(Value::from("limit"), Value::from(f32::NEG_INFINITY)),
+	]);
+	let response = Value::Map(vec![
+		(Value::from("command"), Value::from("dump")),
+		(Value::from("payload"), payload.clone()),
+	]);
+
+	let server = MockServer::start().unwrap();
+	server.respond("dump", MockResponse::Plain(encode(&response)));
+
+	let mut bone = connect(&server, true);
+	let received = bone
+		.send_command_msgpack(&json!({"command": "dump"}))
+		.unwrap();
+
+	assert_eq!(received["payload"]["blob"], payload["blob"]);
+	assert_eq!(received["payload"]["ext"], payload["ext"]);
+	assert_eq!(received["payload"]["counter"].as_u64(), Some(u64::MAX));
+	assert!(received["payload"]["value"].as_f64().unwrap().is_nan());
+
+	assert_eq!(
+		msgpack::render(&received["payload"], false, BinaryFormat::Base64),
+		r#"{"blob":<bin 4 bytes: AAEC/w==>,"ext":<ext 5, 2 bytes: yv4=>,"counter":18446744073709551615,"value":NaN,"limit":-Infinity}"#
+	);
+	assert_eq!(
+		msgpack::render(&received["payload"]["blob"], false, BinaryFormat::Hex),
+		"<bin 4 bytes: 000102ff>"
+	);
+}
+
+#[test]
+fn json_response() {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"channel_data",
+		MockResponse::json(json!({"name": "speed", "value": 2.0, "count": 2, "unit": null})),
+	);
+
+	let mut bone = connect(&server, false);
+	let received = bone
+		.send_command_msgpack(&json!({"command": "channel_data"}))
+		.unwrap();
+
+	assert_eq!(
+		msgpack::render(&received, true, BinaryFormat::Base64),
+		r#"{
+    "payload": {
+        "name": "speed",
+        "value": 2.0,
+        "count": 2,
+        "unit": null
+    },
+    "command": "channel_data"
+}"#
+	);
+}
+
+#[test]
+fn detects_response_encoding() {
+	let response = json!({"command": "date", "payload": {"date": "2021-05-04 21:08:33"}});
+
+	let server = MockServer::start().unwrap();
+	server.respond_once(
+		"date",
+		MockResponse::Plain(rmp_serde::to_vec(&response).unwrap()),
+	);
+	server.respond_once(
+		"date",
+		MockResponse::Plain(response.to_string().into_bytes()),
+	);
+
+	// a JSON client reads a msgpack response and vice versa
+	let mut bone = connect(&server, false);
+	assert_eq!(
+		bone.send_command(&json!({"command": "date"})).unwrap(),
+		response
+	);
+
+	bone.set_msgpack(true);
+	assert!(bone.msgpack());
+	assert_eq!(
+		bone.send_command(&json!({"command": "date"})).unwrap(),
+		response
+	);
+
+	assert!(codec::is_msgpack(&rmp_serde::to_vec(&response).unwrap()));
+	assert!(!codec::is_msgpack(b" {\"command\": \"date\"}"));
+}
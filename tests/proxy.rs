@@ -0,0 +1,155 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+use bone_api::proxy::{Proxy, ProxyKind};
+use bone_api::testing::{MockResponse, MockServer};
+use bone_api::{Bone, BoneError};
+use serde_json::json;
+
+/// Accepts one connection, runs `handshake` on it and then relays to the
+/// address it returns.
+fn fake_proxy(
+	handshake: impl FnOnce(&mut TcpStream) -> Option<SocketAddr> + Send + 'static,
+) -> SocketAddr {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+
+	thread::spawn(move || {
+		let (mut client, _) = listener.accept().unwrap();
+
+		if let Some(target) = handshake(&mut client) {
+			let mut server = TcpStream::connect(target).unwrap();
+			let (mut client_read, mut server_write) =
+				(client.try_clone().unwrap(), server.try_clone().unwrap());
+
+			thread::spawn(move || std::io::copy(&mut client_read, &mut server_write));
+			let _ = std::io::copy(&mut server, &mut client);
+		}
+	});
+
+	addr
+}
+
+fn server() -> MockServer {
+	let server = MockServer::start().unwrap();
+	server.respond(
+		"serial_number",
+		MockResponse::json(json!({"serial_number": "1234"})),
+	);
+	server
+}
+
+fn serial_number(bone: &mut Bone) -> bone_api::Result<String> {
+	let response = bone.send_command(&json!({"command": "serial_number"}))?;
+	Ok(response["payload"]["serial_number"]
+		.as_str()
+		.unwrap_or_default()
+		.to_string())
+}
+
+#[test]
+fn parse() {
+	assert_eq!(
+		Proxy::parse("socks5h://user:p%40ss@[::1]:9050").unwrap(),
+		Proxy {
+			kind: ProxyKind::Socks5h,
+			host: "::1".to_string(),
+			port: 9050,
+			credentials: Some(("user".to_string(), "p@ss".to_string())),
+		}
+	);
+	assert_eq!(
+		Proxy::parse("proxy.example.com").unwrap(),
+		Proxy {
+			kind: ProxyKind::Http,
+			host: "proxy.example.com".to_string(),
+			port: 8080,
+			credentials: None,
+		}
+	);
+	assert!(Proxy::parse("ftp://proxy").is_err());
+	assert!(Proxy::parse("http://proxy:port").is_err());
+}
+
+#[test]
+fn socks5() {
+	let server = server();
+	let target = server.addr();
+
+	let proxy = fake_proxy(move |client| {
+		let mut greeting = [0; 3];
+		client.read_exact(&mut greeting).unwrap();
+		assert_eq!(greeting, [5, 1, 2]);
+		client.write_all(&[5, 2]).unwrap();
+
+		let mut auth = [0; 13];
+		client.read_exact(&mut auth).unwrap();
+		assert_eq!(&auth, b"\x01\x04user\x06secret");
+		client.write_all(&[1, 0]).unwrap();
+
+		let mut request = [0; 10];
+		client.read_exact(&mut request).unwrap();
+		assert_eq!(request[..4], [5, 1, 0, 1]);
+		assert_eq!(request[4..8], [127, 0, 0, 1]);
+		assert_eq!(u16::from_be_bytes([request[8], request[9]]), target.port());
+		client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+
+		Some(target)
+	});
+
+	let mut bone = Bone::new(&server.ip(), &server.port().to_string(), false, false);
+	bone.set_proxy(Proxy::parse(&format!("socks5://user:secret@{}", proxy)).unwrap());
+	bone.connect().unwrap();
+
+	assert_eq!(serial_number(&mut bone).unwrap(), "1234");
+}
+
+#[test]
+fn http_connect() {
+	let server = server();
+	let target = server.addr();
+
+	let proxy = fake_proxy(move |client| {
+		let mut reader = BufReader::new(client.try_clone().unwrap());
+		let mut request = String::new();
+		while !request.ends_with("\r\n\r\n") {
+			reader.read_line(&mut request).unwrap();
+		}
+
+		assert!(request.starts_with(&format!("CONNECT {} HTTP/1.1\r\n", target)));
+		assert!(request.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+		client
+			.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+			.unwrap();
+
+		Some(target)
+	});
+
+	let mut bone = Bone::new(&server.ip(), &server.port().to_string(), false, false);
+	bone.set_proxy(Proxy::parse(&format!("http://user:secret@{}", proxy)).unwrap());
+	bone.connect().unwrap();
+
+	assert_eq!(serial_number(&mut bone).unwrap(), "1234");
+}
+
+#[test]
+fn http_connect_refused() {
+	let proxy = fake_proxy(|client| {
+		let mut buf = [0; 1024];
+		let _ = client.read(&mut buf);
+		client
+			.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")
+			.unwrap();
+
+		None
+	});
+
+	let mut bone = Bone::new("192.0.2.1", "6451", false, false);
+	bone.set_proxy(Proxy::parse(&format!("http://{}", proxy)).unwrap());
+
+	match bone.connect() {
+		Err(BoneError::Proxy(msg)) => assert_eq!(msg, "HTTP/1.1 403 Forbidden"),
+		other => panic!("unexpected result {:?}", other.err()),
+	}
+}
@@ -0,0 +1,428 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bone_api::known_hosts::{self, KnownHosts};
+use bone_api::testing::{MockResponse, MockServer};
+use bone_api::{Bone, BoneError, ClientIdentity, PeerTrust, TlsConfig, TlsIdentity};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslAcceptor, SslMethod, SslVerifyMode};
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use openssl::x509::{X509NameBuilder, X509};
+use serde_json::json;
+
+struct Identity {
+	cert: X509,
+	key: PKey<Private>,
+}
+
+/// Issues a certificate for `common_name`, signed by `issuer` or self-signed.
+fn issue(common_name: &str, alt_names: &[&str], ca: bool, issuer: Option<&Identity>) -> Identity {
+	let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+	let mut name = X509NameBuilder::new().unwrap();
+	name.append_entry_by_text("CN", common_name).unwrap();
+	let name = name.build();
+
+	let mut serial = BigNum::new().unwrap();
+	serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+	let mut builder = X509::builder().unwrap();
+	builder.set_version(2).unwrap();
+	builder
+		.set_serial_number(&serial.to_asn1_integer().unwrap())
+		.unwrap();
+	builder.set_subject_name(&name).unwrap();
+	builder
+		.set_issuer_name(issuer.map_or(&name, |issuer| issuer.cert.subject_name()))
+		.unwrap();
+	builder.set_pubkey(&key).unwrap();
+	builder
+		.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+		.unwrap();
+	builder
+		.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+		.unwrap();
+
+	if ca {
+		builder
+			.append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+			.unwrap();
+	}
+
+	if !alt_names.is_empty() {
+		let mut san = SubjectAlternativeName::new();
+
+		for alt_name in alt_names {
+			match alt_name.parse::<std::net::IpAddr>() {
+				Ok(_) => san.ip(alt_name),
+				Err(_) => san.dns(alt_name),
+			};
+		}
+
+		let san = san.build(&builder.x509v3_context(issuer.map(|i| &*i.cert), None));
+		builder.append_extension(san.unwrap()).unwrap();
+	}
+
+	let signing_key = issuer.map_or(&key, |issuer| &issuer.key);
+	builder.sign(signing_key, MessageDigest::sha256()).unwrap();
+
+	Identity {
+		cert: builder.build(),
+		key,
+	}
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!("bone_api-tls-{}-{}", name, std::process::id()));
+	let _ = fs::remove_dir_all(&dir);
+	fs::create_dir_all(&dir).unwrap();
+	dir
+}
+
+fn write_cert(dir: &Path, name: &str, identity: &Identity) -> PathBuf {
+	let path = dir.join(name);
+	fs::write(&path, identity.cert.to_pem().unwrap()).unwrap();
+	path
+}
+
+fn write_key(dir: &Path, name: &str, identity: &Identity) -> PathBuf {
+	let path = dir.join(name);
+	fs::write(&path, identity.key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+	path
+}
+
+/// Mock device presenting `server`, requiring a client certificate issued by
+/// `client_ca` if given.
+fn start_server(server: &Identity, client_ca: Option<&Identity>) -> MockServer {
+	let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+	acceptor.set_certificate(&server.cert).unwrap();
+	acceptor.set_private_key(&server.key).unwrap();
+
+	if let Some(client_ca) = client_ca {
+		acceptor
+			.cert_store_mut()
+			.add_cert(client_ca.cert.clone())
+			.unwrap();
+		acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+	}
+
+	let server = MockServer::start_tls(acceptor.build()).unwrap();
+	server.respond(
+		"date",
+		MockResponse::json(json!({"date": "2021-05-04 21:08:33"})),
+	);
+	server
+}
+
+/// Connects and sends a command, client certificates are only rejected after
+/// the handshake with TLS 1.3.
+fn connect(server: &MockServer, tls_config: TlsConfig) -> Result<Bone, BoneError> {
+	let mut bone = Bone::new(&server.ip(), &server.port().to_string(), false, true);
+	bone.set_tls_config(tls_config);
+	bone.connect()?;
+	bone.send_command(&json!({"command": "date"}))?;
+	Ok(bone)
+}
+
+#[test]
+fn ca_verification() {
+	let dir = temp_dir("ca_verification");
+	let ca = issue("Test CA", &[], true, None);
+	let device = issue("device", &["127.0.0.1", "device.local"], false, Some(&ca));
+	let ca_file = write_cert(&dir, "ca.pem", &ca);
+	let server = start_server(&device, None);
+
+	let bone = connect(
+		&server,
+		TlsConfig {
+			ca_file: Some(ca_file.clone()),
+			..Default::default()
+		},
+	)
+	.unwrap();
+	assert_eq!(bone.peer_trust(), Some(PeerTrust::CaVerified));
+	assert_eq!(
+		bone.peer_fingerprint(),
+		Some(known_hosts::fingerprint(&device.cert).unwrap().as_str())
+	);
+
+	// unknown CA
+	assert!(matches!(
+		connect(&server, TlsConfig::default()),
+		Err(BoneError::Tls(_))
+	));
+
+	// trusted CA, but issued for another host
+	assert!(matches!(
+		connect(
+			&server,
+			TlsConfig {
+				ca_file: Some(ca_file),
+				identity: TlsIdentity::Hostname(String::from("other.local")),
+				..Default::default()
+			},
+		),
+		Err(BoneError::Tls(_))
+	));
+
+	assert_eq!(
+		connect(&server, TlsConfig::insecure())
+			.unwrap()
+			.peer_trust(),
+		Some(PeerTrust::Unverified)
+	);
+
+	fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn serial_identity() {
+	let dir = temp_dir("serial_identity");
+	let ca = issue("Test CA", &[], true, None);
+	let ca_file = write_cert(&dir, "ca.pem", &ca);
+	let serial_config = |serial| TlsConfig {
+		ca_file: Some(ca_file.clone()),
+		identity: TlsIdentity::Serial(serial),
+		..Default::default()
+	};
+
+	// serial number as common name
+	let server = start_server(&issue("1234", &[], false, Some(&ca)), None);
+	let bone = connect(&server, serial_config(1234)).unwrap();
+	assert_eq!(bone.peer_trust(), Some(PeerTrust::CaVerified));
+	assert!(matches!(
+		connect(&server, serial_config(4321)),
+		Err(BoneError::Certificate(_))
+	));
+
+	// serial number as DNS subject alternative name
+	let server = start_server(&issue("device", &["4321"], false, Some(&ca)), None);
+	assert!(connect(&server, serial_config(4321)).is_ok());
+	assert!(matches!(
+		connect(&server, serial_config(1234)),
+		Err(BoneError::Certificate(_))
+	));
+
+	fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn known_hosts_pinning() {
+	let dir = temp_dir("known_hosts_pinning");
+	let known_hosts_path = dir.join("known_hosts");
+	let device = issue("device", &["127.0.0.1"], false, None);
+	let server = start_server(&device, None);
+	let tls_config = TlsConfig {
+		known_hosts: Some(known_hosts_path.clone()),
+		..Default::default()
+	};
+
+	// unknown device, trusted on first use
+	let bone = connect(&server, tls_config.clone()).unwrap();
+	assert_eq!(bone.peer_trust(), Some(PeerTrust::FirstUse));
+
+	let name = bone.known_hosts_name();
+	let fingerprint = known_hosts::fingerprint(&device.cert).unwrap();
+	let known_hosts = KnownHosts::load(&known_hosts_path).unwrap();
+	assert_eq!(known_hosts.get(&name), Some(fingerprint.as_str()));
+
+	let bone = connect(&server, tls_config.clone()).unwrap();
+	assert_eq!(bone.peer_trust(), Some(PeerTrust::Pinned));
+
+	// the device presents another certificate
+	let server = start_server(&issue("device", &["127.0.0.1"], false, None), None);
+	let mut known_hosts = KnownHosts::load(&known_hosts_path).unwrap();
+	known_hosts.insert(&format!("[127.0.0.1]:{}", server.port()), &fingerprint);
+	known_hosts.save().unwrap();
+
+	match connect(&server, tls_config) {
+		Err(BoneError::FingerprintMismatch {
+			name,
+			known,
+			presented,
+		}) => {
+			assert_eq!(name, format!("[127.0.0.1]:{}", server.port()));
+			assert_eq!(known, fingerprint);
+			assert_ne!(presented, fingerprint);
+		}
+		other => panic!("expected a fingerprint mismatch, got {:?}", other.err()),
+	}
+
+	fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn deferred_pinning() {
+	let dir = temp_dir("deferred_pinning");
+	let known_hosts_path = dir.join("known_hosts");
+	let server = start_server(&issue("device", &["127.0.0.1"], false, None), None);
+	let tls_config = TlsConfig {
+		known_hosts: Some(known_hosts_path.clone()),
+		pin_first_use: false,
+		..Default::default()
+	};
+
+	// connections that are not kept leave the known hosts alone
+	let first = connect(&server, tls_config.clone()).unwrap();
+	let second = connect(&server, tls_config.clone()).unwrap();
+	assert_eq!(first.peer_trust(), Some(PeerTrust::FirstUse));
+	assert!(!known_hosts_path.exists());
+
+	first.pin_peer().unwrap();
+	let known_hosts = KnownHosts::load(&known_hosts_path).unwrap();
+	assert_eq!(
+		known_hosts.get(&first.known_hosts_name()),
+		first.peer_fingerprint()
+	);
+
+	// pinning again with the same certificate changes nothing
+	second.pin_peer().unwrap();
+	assert_eq!(
+		connect(&server, tls_config.clone()).unwrap().peer_trust(),
+		Some(PeerTrust::Pinned)
+	);
+
+	// another certificate was pinned in the meantime
+	let mut known_hosts = KnownHosts::load(&known_hosts_path).unwrap();
+	known_hosts.insert(&second.known_hosts_name(), "sha256:00");
+	known_hosts.save().unwrap();
+	assert!(matches!(
+		second.pin_peer(),
+		Err(BoneError::FingerprintMismatch { .. })
+	));
+
+	fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn client_certificates() {
+	let dir = temp_dir("client_certificates");
+	let ca = issue("Test CA", &[], true, None);
+	let client_ca = issue("Client CA", &[], true, None);
+	let ca_file = write_cert(&dir, "ca.pem", &ca);
+
+	let device = issue("device", &["127.0.0.1"], false, Some(&ca));
+	let server = start_server(&device, Some(&client_ca));
+	let client_config = |client_identity| TlsConfig {
+		ca_file: Some(ca_file.clone()),
+		client_identity,
+		..Default::default()
+	};
+
+	let client = issue("client", &[], false, Some(&client_ca));
+	let pem = ClientIdentity::Pem {
+		cert: write_cert(&dir, "client.pem", &client),
+		key: write_key(&dir, "client.key", &client),
+	};
+	assert!(connect(&server, client_config(Some(pem))).is_ok());
+
+	let pkcs12_path = dir.join("client.p12");
+	let mut pkcs12 = Pkcs12::builder();
+	pkcs12.name("client").pkey(&client.key).cert(&client.cert);
+	fs::write(
+		&pkcs12_path,
+		pkcs12.build2("secret").unwrap().to_der().unwrap(),
+	)
+	.unwrap();
+
+	let pkcs12 = ClientIdentity::Pkcs12 {
+		path: pkcs12_path.clone(),
+		password: String::from("secret"),
+	};
+	assert!(connect(&server, client_config(Some(pkcs12))).is_ok());
+
+	let wrong_password = ClientIdentity::Pkcs12 {
+		path: pkcs12_path,
+		password: String::from("wrong"),
+	};
+	assert!(matches!(
+		connect(&server, client_config(Some(wrong_password))),
+		Err(BoneError::Tls(_))
+	));
+
+	// no client certificate
+	assert!(connect(&server, client_config(None)).is_err());
+
+	// client certificate from an unknown CA
+	let stranger = issue("client", &[], false, None);
+	let stranger = ClientIdentity::Pem {
+		cert: write_cert(&dir, "stranger.pem", &stranger),
+		key: write_key(&dir, "stranger.key", &stranger),
+	};
+	assert!(connect(&server, client_config(Some(stranger))).is_err());
+
+	fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn async_known_hosts_pinning() {
+	let dir = temp_dir("async_known_hosts_pinning");
+	let known_hosts_path = dir.join("known_hosts");
+	let server = start_server(&issue("device", &["127.0.0.1"], false, None), None);
+
+	for expected in [PeerTrust::FirstUse, PeerTrust::Pinned] {
+		let mut bone =
+			bone_api::AsyncBone::new(&server.ip(), &server.port().to_string(), false, true);
+		bone.set_tls_config(TlsConfig {
+			known_hosts: Some(known_hosts_path.clone()),
+			..Default::default()
+		});
+		bone.connect().await.unwrap();
+
+		assert_eq!(bone.peer_trust(), Some(expected));
+		assert!(bone.date().await.is_ok());
+	}
+
+	fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn tls_unavailable() {
+	use std::io::Write;
+	use std::net::TcpListener;
+
+	// accepts one connection, answers with `answer` and closes it
+	let listen = |answer: &'static [u8]| {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+
+		std::thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let _ = stream.write_all(answer);
+		});
+
+		port
+	};
+	let connect_port = |port: u16| {
+		let mut bone = Bone::new("127.0.0.1", &port.to_string(), false, true);
+		bone.connect().err().unwrap()
+	};
+
+	// nothing listens on the port
+	let port = TcpListener::bind("127.0.0.1:0")
+		.unwrap()
+		.local_addr()
+		.unwrap()
+		.port();
+	assert!(connect_port(port).is_tls_unavailable());
+
+	// closed before the device sent anything
+	assert!(connect_port(listen(b"")).is_tls_unavailable());
+
+	// the device sent something that is no TLS
+	let error = connect_port(listen(b"00000002{}"));
+	assert!(matches!(error, BoneError::Tls(_)));
+	assert!(!error.is_tls_unavailable());
+
+	// the certificate can't be trusted
+	let server = start_server(&issue("device", &["127.0.0.1"], false, None), None);
+	let error = connect(&server, TlsConfig::default()).err().unwrap();
+	assert!(matches!(error, BoneError::Tls(_)));
+	assert!(!error.is_tls_unavailable());
+}